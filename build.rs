@@ -1,6 +1,10 @@
+use std::{env, fs, path::Path};
+
 use vergen_gix::{Emitter, GixBuilder};
 
 fn main() {
+    ktls();
+
     if let Ok(gix) = GixBuilder::default().sha(true).build() {
         if Emitter::new().fail_on_error().add_instructions(&gix).and_then(|e| e.emit()).is_ok() {
             return;
@@ -10,3 +14,60 @@ fn main() {
     // Fallback
     println!("cargo:rustc-env=VERGEN_GIT_SHA=unknown");
 }
+
+/// Enable kernel TLS only with the vendored OpenSSL built with kTLS,
+/// constants not exposed by openssl-sys are read from its headers so they match the linked library.
+fn ktls() {
+    println!("cargo:rustc-check-cfg=cfg(ktls)");
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("linux") || env::var_os("DEP_OPENSSL_VENDORED").is_none() {
+        return;
+    }
+    let Some(include) = env::var_os("DEP_OPENSSL_INCLUDE") else {
+        return;
+    };
+
+    let dir = Path::new(&include).join("openssl");
+    let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap_or_default();
+    if read("configuration.h").contains("OPENSSL_NO_KTLS") {
+        println!("cargo:warning=Vendored OpenSSL is built without kTLS, kernel TLS disabled");
+        return;
+    }
+    let (ssl, bio) = (read("ssl.h"), read("bio.h"));
+    let op_bit = define(&ssl, "SSL_OP_ENABLE_KTLS").and_then(|v| v.strip_prefix("SSL_OP_BIT(")?.strip_suffix(')')?.parse::<u32>().ok());
+    let (Some(op_bit), Some(get_ktls_send), Some(noclose)) = (
+        op_bit,
+        define(&bio, "BIO_CTRL_GET_KTLS_SEND").and_then(parse_int),
+        define(&bio, "BIO_NOCLOSE").and_then(parse_int),
+    ) else {
+        println!("cargo:warning=kTLS constants not found in OpenSSL headers, kernel TLS disabled");
+        return;
+    };
+
+    let consts = format!(
+        "pub(super) const SSL_OP_ENABLE_KTLS: u64 = 1 << {op_bit};\n\
+        const BIO_CTRL_GET_KTLS_SEND: c_int = {get_ktls_send};\n\
+        const BIO_NOCLOSE: c_int = {noclose};\n"
+    );
+    fs::write(Path::new(&env::var_os("OUT_DIR").unwrap()).join("ktls_consts.rs"), consts).unwrap();
+    println!("cargo:rustc-cfg=ktls");
+}
+
+/// Value of `# define NAME VALUE` in a header.
+fn define<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.lines().find_map(|line| {
+        let mut parts = line
+            .trim_start()
+            .strip_prefix('#')?
+            .trim_start()
+            .strip_prefix("define ")?
+            .split_whitespace();
+        (parts.next()? == name).then(|| parts.next()).flatten()
+    })
+}
+
+fn parse_int(value: &str) -> Option<i32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
@@ -0,0 +1,172 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering::Relaxed},
+    Arc,
+};
+
+use log::{debug, info, warn};
+use tokio::sync::Notify;
+
+use crate::cache_manager::{CacheFileInfo, CacheManager, HOT_MIN_FREQUENCY};
+
+/// Decide which cache misses are stored, based on disk usage watermarks.
+///
+/// Below the low watermark every miss is stored. Between the watermarks only files requested more than once are stored.
+/// Above the high watermark misses are only streamed through and eviction starts right away.
+pub(super) struct Admission {
+    /// Usage percent which eviction frees space down to.
+    low: u8,
+    /// Usage percent which starts eviction.
+    high: u8,
+    /// Wake the background task to evict now.
+    pub(super) evict_notify: Arc<Notify>,
+    stored: AtomicU64,
+    streamed: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Pressure {
+    Normal,
+    Elevated,
+    Critical,
+}
+
+/// Cache usage of the whole cache (`volume` is None) or a volume.
+pub(super) struct Usage {
+    pub volume: Option<usize>,
+    pub name: String,
+    pub used: u64,
+    /// Size limit, or used plus free disk space if the disk is smaller.
+    pub capacity: u64,
+}
+
+impl Usage {
+    /// Bytes at `percent` of the capacity.
+    pub fn watermark(&self, percent: u8) -> u64 {
+        (self.capacity as u128 * percent as u128 / 100) as u64
+    }
+}
+
+impl Admission {
+    pub fn new(low: u8, high: u8) -> Self {
+        let low = if low > high {
+            warn!(
+                "Cache low watermark {}% is higher than high watermark {}%, use {}%",
+                low, high, high
+            );
+            high
+        } else {
+            low
+        };
+        Self {
+            low,
+            high,
+            evict_notify: Arc::new(Notify::new()),
+            stored: AtomicU64::new(0),
+            streamed: AtomicU64::new(0),
+        }
+    }
+
+    fn pressure(&self, usage: &Usage, extra: u64) -> Pressure {
+        let used = usage.used.saturating_add(extra);
+        if used > usage.watermark(self.high) {
+            Pressure::Critical
+        } else if used > usage.watermark(self.low) {
+            Pressure::Elevated
+        } else {
+            Pressure::Normal
+        }
+    }
+}
+
+impl CacheManager {
+    /// Usage of the whole cache and each healthy volume.
+    pub(super) fn cache_usage(&self) -> Vec<Usage> {
+        let mut list = vec![Usage {
+            volume: None,
+            name: String::from("total"),
+            used: self.total_size.load(Relaxed),
+            capacity: self.size_limit.load(Relaxed),
+        }];
+
+        let usage = self.volume_usage();
+        for (index, volume) in self.store.volumes().into_iter().enumerate() {
+            if !volume.healthy {
+                continue;
+            }
+            let used = usage.get(index).copied().unwrap_or_default();
+            let disk = volume.available_space.map_or(u64::MAX, |free| used.saturating_add(free));
+            list.push(Usage {
+                volume: Some(index),
+                name: volume.name,
+                used,
+                capacity: volume.size_limit.min(disk),
+            });
+        }
+        list
+    }
+
+    /// Highest pressure of the whole cache and the volume which would hold the static range, after adding `extra` bytes.
+    fn range_pressure(&self, range: &str, extra: u64) -> Pressure {
+        // New range can be placed on any volume, count the worst one.
+        let volume = self.store.range_volume(range);
+        self.cache_usage()
+            .iter()
+            .filter(|u| u.volume.is_none() || volume.is_none() || u.volume == volume)
+            .map(|u| self.admission.pressure(u, extra))
+            .max()
+            .unwrap_or(Pressure::Normal)
+    }
+
+    /// Decide whether a cache miss should be stored after download, or only streamed to the client.
+    pub fn should_store(&self, info: &CacheFileInfo) -> bool {
+        let pressure = self.range_pressure(&info.static_range(), info.size() as u64);
+        let store = match pressure {
+            Pressure::Normal => true,
+            Pressure::Elevated => self.frequency.estimate(&info.hash()) >= HOT_MIN_FREQUENCY,
+            Pressure::Critical => false,
+        };
+
+        if store {
+            self.admission.stored.fetch_add(1, Relaxed);
+        } else {
+            debug!("Stream through cache miss: file={}, pressure={:?}", info.file_id(), pressure);
+            self.admission.streamed.fetch_add(1, Relaxed);
+        }
+        if pressure == Pressure::Critical {
+            self.admission.evict_notify.notify_one();
+        }
+        store
+    }
+
+    /// Start eviction now if the import pushed usage over the high watermark.
+    pub(super) fn check_pressure(&self, info: &CacheFileInfo) {
+        if self.range_pressure(&info.static_range(), 0) == Pressure::Critical {
+            self.admission.evict_notify.notify_one();
+        }
+    }
+
+    /// Free space down to the low watermark for the whole cache and volumes above the high watermark.
+    pub(super) async fn check_cache_usage(&self) {
+        for usage in self.cache_usage() {
+            if self.admission.pressure(&usage, 0) != Pressure::Critical {
+                continue;
+            }
+            let need_free = usage.used.saturating_sub(usage.watermark(self.admission.low));
+            debug!(
+                "Cache usage is above high watermark: volume={}, used={}MiB, capacity={}MiB",
+                usage.name,
+                usage.used / 1024 / 1024,
+                usage.capacity / 1024 / 1024
+            );
+            self.free_cache(need_free, usage.volume).await;
+        }
+    }
+
+    pub(super) fn log_admission_stats(&self) {
+        info!(
+            "Cache admission: stored={}, streamed={}",
+            self.admission.stored.load(Relaxed),
+            self.admission.streamed.load(Relaxed)
+        );
+    }
+}
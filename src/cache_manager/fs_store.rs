@@ -0,0 +1,540 @@
+use std::{
+    collections::HashMap,
+    io::Error,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::async_trait;
+use filesize::{file_real_size, file_real_size_fast};
+use filetime::{set_file_mtime, FileTime};
+use log::{debug, error, info, warn};
+use parking_lot::{Mutex, RwLock};
+use tempfile::TempPath;
+use tokio::{
+    fs::{metadata, read_dir, remove_dir_all},
+    task::spawn_blocking,
+};
+
+use crate::cache_manager::{
+    journal::{import_temp_path, sync_dir, ImportJournal, JOURNAL_FILE},
+    store::{CacheEntry, CacheFile, CacheStore, VolumeInfo},
+    CacheFileInfo,
+};
+
+const ROOT_RETRY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Default store, files are saved in a two level directory tree. e.g. `ab/cd/abcd...-size-xres-yres-type`
+///
+/// Static ranges can be spread across multiple cache roots, each range lives in exactly one root.
+pub struct FsStore {
+    roots: Vec<CacheRoot>,
+    placement: RwLock<HashMap<String, usize>>,
+}
+
+pub struct CacheRoot {
+    path: PathBuf,
+    size_limit: u64,
+    failed_at: Mutex<Option<Instant>>,
+    journal: Arc<ImportJournal>,
+}
+
+impl CacheRoot {
+    /// Create a cache root, `size_limit` 0 means no limit.
+    pub fn new<P: AsRef<Path>>(path: P, size_limit: u64) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            size_limit: if size_limit == 0 { u64::MAX } else { size_limit },
+            failed_at: Mutex::new(None),
+            journal: Arc::new(ImportJournal::new(path.as_ref())),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Failed root will be retried after a while.
+    fn is_healthy(&self) -> bool {
+        self.failed_at.lock().map_or(true, |time| time.elapsed() > ROOT_RETRY_INTERVAL)
+    }
+
+    fn capacity(&self) -> u64 {
+        if self.size_limit != u64::MAX {
+            self.size_limit
+        } else {
+            get_disk_space(&self.path).map_or(1, |(_, total)| total)
+        }
+    }
+}
+
+impl FsStore {
+    /// Create the store and find which root holds each static range, blocks on directory listing.
+    ///
+    /// Placement must be known before serving, otherwise cached files look missing
+    /// and a new import may place an existing range on another root.
+    pub fn new(roots: Vec<CacheRoot>) -> Self {
+        assert!(!roots.is_empty(), "At least one cache dir is required");
+        let paths: Vec<PathBuf> = roots.iter().map(|root| root.path.clone()).collect();
+        let (placement, errors) = scan_placement(&paths, &HashMap::new());
+        for (index, err) in errors {
+            let root = &roots[index];
+            error!("Read cache dir {:?} error: {}", root.path, err);
+            if std::fs::metadata(&root.path).is_err() {
+                *root.failed_at.lock() = Some(Instant::now());
+            }
+        }
+        Self {
+            roots,
+            placement: RwLock::new(placement),
+        }
+    }
+
+    /// Roll back imports interrupted by crash in every root, must run before any import.
+    pub fn recover(&self) {
+        for root in &self.roots {
+            match root.journal.recover() {
+                Ok(0) => (),
+                Ok(count) => warn!("Rolled back {} unfinished imports in cache dir {:?}", count, root.path),
+                Err(err) => error!("Recover import journal in cache dir {:?} error: {}", root.path, err),
+            }
+        }
+    }
+
+    fn range_root(&self, range: &str) -> Option<&CacheRoot> {
+        self.placement.read().get(range).map(|index| &self.roots[*index])
+    }
+
+    fn range_dir(root: &Path, range: &str) -> PathBuf {
+        root.join(&range[0..2]).join(&range[2..4])
+    }
+
+    /// Select a root for new static range, prefer the root with least ranges relative to capacity.
+    fn place_range(&self, range: &str) -> Option<&CacheRoot> {
+        let mut placement = self.placement.write();
+        if let Some(index) = placement.get(range) {
+            if self.roots[*index].is_healthy() {
+                return Some(&self.roots[*index]);
+            }
+        }
+
+        let mut count = vec![0u64; self.roots.len()];
+        for index in placement.values() {
+            count[*index] += 1;
+        }
+        let (index, _) = self
+            .roots
+            .iter()
+            .enumerate()
+            .filter(|(_, root)| root.is_healthy())
+            .min_by(|(a, root_a), (b, root_b)| {
+                let a = (count[*a] + 1) as f64 / root_a.capacity() as f64;
+                let b = (count[*b] + 1) as f64 / root_b.capacity() as f64;
+                a.total_cmp(&b)
+            })?;
+
+        if let Some(old) = placement.insert(range.to_string(), index) {
+            warn!(
+                "Move static range {} from failed cache dir {:?} to {:?}",
+                range, self.roots[old].path, self.roots[index].path
+            );
+        } else {
+            debug!("Place static range {} to cache dir {:?}", range, self.roots[index].path);
+        }
+        Some(&self.roots[index])
+    }
+
+    /// Mark root as failed if it is not accessible.
+    async fn check_root(&self, root: &CacheRoot, err: &Error) {
+        if metadata(&root.path).await.is_err() {
+            error!("Cache dir {:?} failed, disable it for a while: {}", root.path, err);
+            *root.failed_at.lock() = Some(Instant::now());
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for FsStore {
+    async fn get(&self, info: &CacheFileInfo) -> Option<CacheFile> {
+        let root = self.range_root(&info.static_range()).filter(|root| root.is_healthy())?;
+        info.get_file(&root.path).await.map(CacheFile::Path)
+    }
+
+    async fn import(&self, info: &CacheFileInfo, file_path: &TempPath) -> Result<u64, Error> {
+        let root = self
+            .place_range(&info.static_range())
+            .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "No available cache dir"))?;
+        let path = info.to_path(&root.path);
+
+        let journal = root.journal.clone();
+        let src = file_path.to_path_buf();
+        let dst = path.clone();
+        let result = spawn_blocking(move || import_file(&journal, &src, &dst)).await?;
+        if let Err(err) = result {
+            self.check_root(root, &err).await;
+            return Err(err);
+        }
+
+        // Fix permission
+        fix_permission(&path).await;
+
+        spawn_blocking(move || file_real_size(&path)).await?
+    }
+
+    async fn remove(&self, info: &CacheFileInfo) -> Result<Option<u64>, Error> {
+        let root = match self.range_root(&info.static_range()) {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        let path = info.to_path(&root.path);
+        if metadata(&path).await.is_err() {
+            return Ok(None);
+        }
+
+        debug!("Delete cache: {:?}", path);
+        spawn_blocking(move || {
+            let size = file_real_size(&path)?;
+            std::fs::remove_file(&path)?;
+            Ok(Some(size))
+        })
+        .await?
+    }
+
+    async fn touch(&self, info: &CacheFileInfo, cutoff: FileTime) {
+        let path = match self.range_root(&info.static_range()) {
+            Some(root) => info.to_path(&root.path),
+            None => return,
+        };
+        if let Ok(metadata) = metadata(&path).await {
+            if FileTime::from_last_modification_time(&metadata) < cutoff {
+                // Update file modification time
+                let _ = spawn_blocking(move || {
+                    if let Err(err) = set_file_mtime(&path, FileTime::now()) {
+                        error!("Update cache file time error: path={:?}, err={}", &path, err);
+                    }
+                })
+                .await;
+            }
+        }
+    }
+
+    async fn list_ranges(&self) -> Result<Vec<String>, Error> {
+        let paths: Vec<PathBuf> = self.roots.iter().map(|root| root.path.clone()).collect();
+        let current = self.placement.read().clone();
+        let (placement, errors) = spawn_blocking(move || scan_placement(&paths, &current)).await?;
+        let mut last_error = None;
+        for (index, err) in errors {
+            let root = &self.roots[index];
+            error!("Read cache dir {:?} error: {}", root.path, err);
+            self.check_root(root, &err).await;
+            last_error = Some(err);
+        }
+
+        // All cache dirs failed
+        if let Some(err) = last_error {
+            if placement.is_empty() {
+                return Err(err);
+            }
+        }
+
+        if self.roots.len() > 1 {
+            let mut count = vec![0; self.roots.len()];
+            for index in placement.values() {
+                count[*index] += 1;
+            }
+            for (root, count) in self.roots.iter().zip(count) {
+                info!("Found {} static ranges in cache dir {:?}", count, root.path);
+            }
+        }
+
+        let ranges = placement.keys().cloned().collect();
+        *self.placement.write() = placement;
+        Ok(ranges)
+    }
+
+    async fn remove_range(&self, range: &str) -> Result<(), Error> {
+        let index = self.placement.write().remove(range);
+        match index {
+            Some(index) => remove_dir_all(FsStore::range_dir(&self.roots[index].path, range)).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn list_files(&self, range: &str) -> Result<Vec<CacheEntry>, Error> {
+        let root = self
+            .range_root(range)
+            .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "Static range not found"))?;
+        let result = list_range_files(&FsStore::range_dir(&root.path, range)).await;
+        if let Err(err) = &result {
+            self.check_root(root, err).await;
+        }
+        result
+    }
+
+    async fn range_mtime(&self, range: &str) -> Option<FileTime> {
+        metadata(FsStore::range_dir(&self.range_root(range)?.path, range))
+            .await
+            .ok()
+            .map(|m| FileTime::from_last_modification_time(&m))
+    }
+
+    fn range_volume(&self, range: &str) -> Option<usize> {
+        self.placement.read().get(range).copied()
+    }
+
+    fn volumes(&self) -> Vec<VolumeInfo> {
+        self.roots
+            .iter()
+            .map(|root| VolumeInfo {
+                name: root.path.to_string_lossy().to_string(),
+                size_limit: root.size_limit,
+                available_space: get_disk_space(&root.path).map(|(free, _)| free),
+                healthy: root.is_healthy(),
+            })
+            .collect()
+    }
+}
+
+/// Move the file into cache atomically and durably, the import is journaled so it can be rolled back after crash.
+fn import_file(journal: &ImportJournal, src: &Path, dst: &Path) -> Result<(), Error> {
+    let dir = dst.parent().unwrap();
+    if std::fs::metadata(dir).is_err() {
+        std::fs::create_dir_all(dir)?;
+        sync_dir(dir.parent().unwrap())?;
+    }
+
+    journal.begin(dst)?;
+    let result = (|| {
+        std::fs::OpenOptions::new().write(true).open(src)?.sync_all()?;
+        if std::fs::rename(src, dst).is_err() {
+            // Can't cross fs move file, copy to a sibling temp file then rename.
+            let temp = import_temp_path(dst);
+            let copied = std::fs::copy(src, &temp)
+                .and_then(|_| std::fs::OpenOptions::new().write(true).open(&temp)?.sync_all())
+                .and_then(|_| std::fs::rename(&temp, dst));
+            if copied.is_err() {
+                let _ = std::fs::remove_file(&temp);
+            }
+            copied?;
+        }
+        sync_dir(dir)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(dst);
+    }
+    journal.end(dst);
+    result
+}
+
+/// Record ranges found in root `index`, the root listed first wins if a range is in multiple roots.
+/// Find which root holds each static range, return the placement and roots failed to read.
+///
+/// A range found in multiple roots was placed again while its root was failing. Keep the copy in `current` placement,
+/// or the most recently modified one, and remove the others so the stale copy never comes back.
+fn scan_placement(paths: &[PathBuf], current: &HashMap<String, usize>) -> (HashMap<String, usize>, Vec<(usize, Error)>) {
+    let mut found: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut errors = Vec::new();
+    for (index, path) in paths.iter().enumerate() {
+        match list_root_ranges(path) {
+            Ok(ranges) => ranges.into_iter().for_each(|range| found.entry(range).or_default().push(index)),
+            Err(err) => errors.push((index, err)),
+        }
+    }
+
+    let placement = found
+        .into_iter()
+        .map(|(range, indexes)| {
+            if let [index] = indexes[..] {
+                return (range, index);
+            }
+            let used = current
+                .get(&range)
+                .copied()
+                .filter(|index| indexes.contains(index))
+                .unwrap_or_else(|| {
+                    let mtime = |index: usize| {
+                        std::fs::metadata(FsStore::range_dir(&paths[index], &range))
+                            .map(|m| FileTime::from_last_modification_time(&m))
+                            .unwrap_or_else(|_| FileTime::zero())
+                    };
+                    indexes.iter().copied().max_by_key(|index| mtime(*index)).unwrap()
+                });
+            for index in indexes.into_iter().filter(|index| *index != used) {
+                let dir = FsStore::range_dir(&paths[index], &range);
+                warn!(
+                    "Static range {} found in multiple cache dirs, remove stale copy {:?}. Used: {:?}",
+                    range, dir, paths[used]
+                );
+                if let Err(err) = std::fs::remove_dir_all(&dir) {
+                    error!("Remove stale static range error: path={:?}, err={}", dir, err);
+                }
+            }
+            (range, used)
+        })
+        .collect();
+    (placement, errors)
+}
+
+fn list_root_ranges(path: &Path) -> Result<Vec<String>, Error> {
+    let mut ranges = Vec::new();
+
+    for l1 in std::fs::read_dir(path)? {
+        let l1 = l1?;
+        let l1_path = l1.path();
+        if l1.file_name() == JOURNAL_FILE {
+            continue;
+        }
+        if !l1_path.is_dir() {
+            warn!("Found unexpected file in cache dir: {}", l1_path.to_str().unwrap_or_default());
+            continue;
+        };
+
+        for l2 in std::fs::read_dir(&l1_path)? {
+            let l2 = l2?;
+            let l2_path = l2.path();
+            if !l2_path.is_dir() {
+                warn!("Found unexpected file in cache dir: {}", l2_path.to_str().unwrap_or_default());
+                continue;
+            };
+
+            let mut hash = l1.file_name().clone();
+            hash.push(l2.file_name());
+            match hash.into_string() {
+                Ok(range) if range.len() == 4 => ranges.push(range),
+                _ => warn!("Found unexpected dir in cache dir: {}", l2_path.to_str().unwrap_or_default()),
+            }
+        }
+    }
+
+    Ok(ranges)
+}
+
+async fn list_range_files(dir: &Path) -> Result<Vec<CacheEntry>, Error> {
+    let mut files = Vec::new();
+    let mut stream = read_dir(dir).await?;
+    while let Some(entry) = stream.next_entry().await? {
+        let path = entry.path();
+        let metadata = metadata(&path).await;
+        if let Err(err) = metadata {
+            error!("Read cache file metadata error: path={:?}, err={}", path, err);
+            continue;
+        }
+        let metadata = metadata.unwrap();
+        if metadata.is_dir() {
+            warn!("Found unexpected dir in cache dir: {:?}", path);
+            continue;
+        }
+
+        // Parse info
+        let info = entry.file_name().to_str().and_then(CacheFileInfo::from_file_id);
+        if info.is_none() {
+            warn!("Invalid cache file: {:?}", path);
+            continue;
+        }
+
+        files.push(CacheEntry {
+            info: info.unwrap(),
+            len: metadata.len(),
+            size: file_real_size_fast(&path, &metadata)?,
+            mtime: FileTime::from_last_modification_time(&metadata),
+        });
+    }
+
+    Ok(files)
+}
+
+#[cfg(unix)]
+async fn fix_permission(path: &Path) {
+    use std::os::unix::prelude::PermissionsExt;
+
+    use tokio::fs::set_permissions;
+
+    _ = set_permissions(&path, PermissionsExt::from_mode(0o644)).await;
+}
+
+#[cfg(not(unix))]
+async fn fix_permission(_path: &Path) {
+    // Skip
+}
+
+/// Get available and total space of the disk.
+#[cfg(unix)]
+fn get_disk_space(path: &Path) -> Option<(u64, u64)> {
+    use rustix::fs::statvfs;
+    if let Ok(stat) = statvfs(path) {
+        Some((stat.f_bavail * stat.f_bsize, stat.f_blocks * stat.f_frsize))
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+fn get_disk_space(path: &Path) -> Option<(u64, u64)> {
+    use windows::{core::HSTRING, Win32::Storage::FileSystem::GetDiskFreeSpaceExW};
+
+    let full_path = path.canonicalize().ok()?;
+    let mut free: u64 = 0;
+    let mut total: u64 = 0;
+
+    if unsafe { GetDiskFreeSpaceExW(&HSTRING::from(full_path.as_path()), Some(&mut free), Some(&mut total), None) }.is_ok() {
+        Some((free, total))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn get_disk_space(_path: &Path) -> Option<(u64, u64)> {
+    None // Not support
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, write};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::cache_manager::tests::test_file_info;
+
+    #[tokio::test]
+    async fn placement_known_before_scan() {
+        let (dir1, dir2) = (tempdir().unwrap(), tempdir().unwrap());
+        let data = b"placement test";
+        let info = test_file_info(data);
+        let path = info.to_path(dir2.path());
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(&path, data).unwrap();
+
+        let store = FsStore::new(vec![CacheRoot::new(dir1.path(), 0), CacheRoot::new(dir2.path(), 0)]);
+        assert_eq!(store.range_volume(&info.static_range()), Some(1));
+        assert!(matches!(store.get(&info).await, Some(CacheFile::Path(p)) if p == path));
+        let size = file_real_size(&path).unwrap();
+        assert_eq!(store.remove(&info).await.unwrap(), Some(size));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn duplicate_range_keep_newest() {
+        let (dir1, dir2) = (tempdir().unwrap(), tempdir().unwrap());
+        let paths = vec![dir1.path().to_path_buf(), dir2.path().to_path_buf()];
+        let (stale, used) = (FsStore::range_dir(&paths[0], "abcd"), FsStore::range_dir(&paths[1], "abcd"));
+        create_dir_all(&stale).unwrap();
+        create_dir_all(&used).unwrap();
+        set_file_mtime(&stale, FileTime::from_unix_time(1_000_000, 0)).unwrap();
+
+        let (placement, errors) = scan_placement(&paths, &HashMap::new());
+        assert!(errors.is_empty());
+        assert_eq!(placement.get("abcd"), Some(&1));
+        assert!(!stale.exists());
+        assert!(used.exists());
+
+        // Current placement wins over mtime
+        create_dir_all(&stale).unwrap();
+        let (placement, _) = scan_placement(&paths, &HashMap::from([("abcd".to_string(), 0)]));
+        assert_eq!(placement.get("abcd"), Some(&0));
+        assert!(stale.exists());
+        assert!(!used.exists());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::cache_manager::CacheFileInfo;
+
+/// Max share of the budget a single file can take, so few large files can't flush the whole tier.
+const MAX_OBJECT_RATIO: u64 = 16;
+
+/// RAM tier keeping the hottest cache files, evicted in LRU order within a fixed byte budget.
+pub(super) struct HotCache {
+    budget: u64,
+    state: Mutex<HotState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct HotState {
+    entries: HashMap<CacheFileInfo, (Bytes, u64)>,
+    /// Access tick to file, the first one is least recently used.
+    order: BTreeMap<u64, CacheFileInfo>,
+    used: u64,
+    tick: u64,
+}
+
+pub struct HotCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub used: u64,
+    pub budget: u64,
+}
+
+impl HotCache {
+    pub fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            state: Mutex::new(HotState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub fn get(&self, info: &CacheFileInfo) -> Option<Bytes> {
+        let mut state = self.state.lock();
+        state.tick += 1;
+        let tick = state.tick;
+        let HotState { entries, order, .. } = &mut *state;
+        match entries.get_mut(info) {
+            Some((data, last)) => {
+                order.remove(last);
+                order.insert(tick, info.clone());
+                *last = tick;
+                self.hits.fetch_add(1, Relaxed);
+                Some(data.clone())
+            }
+            None => {
+                self.misses.fetch_add(1, Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Whether a file with the size is allowed to enter the tier.
+    pub fn admissible(&self, size: u64) -> bool {
+        self.is_enabled() && size <= self.budget / MAX_OBJECT_RATIO
+    }
+
+    pub fn insert(&self, info: &CacheFileInfo, data: Bytes) {
+        let size = data.len() as u64;
+        if !self.admissible(size) {
+            return;
+        }
+
+        let mut state = self.state.lock();
+        if state.entries.contains_key(info) {
+            return;
+        }
+        while state.used + size > self.budget {
+            let Some((_, victim)) = state.order.pop_first() else {
+                break;
+            };
+            if let Some((data, _)) = state.entries.remove(&victim) {
+                state.used -= data.len() as u64;
+            }
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        state.order.insert(tick, info.clone());
+        state.entries.insert(info.clone(), (data, tick));
+        state.used += size;
+    }
+
+    pub fn remove(&self, info: &CacheFileInfo) {
+        let mut state = self.state.lock();
+        if let Some((data, tick)) = state.entries.remove(info) {
+            state.order.remove(&tick);
+            state.used -= data.len() as u64;
+        }
+    }
+
+    /// Drop all files of a static range.
+    pub fn remove_range(&self, range: &str) {
+        let mut state = self.state.lock();
+        let HotState { entries, order, used, .. } = &mut *state;
+        entries.retain(|info, (data, tick)| {
+            if !info.static_range().eq_ignore_ascii_case(range) {
+                return true;
+            }
+            order.remove(tick);
+            *used -= data.len() as u64;
+            false
+        });
+    }
+
+    pub fn stats(&self) -> HotCacheStats {
+        let state = self.state.lock();
+        HotCacheStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            entries: state.entries.len(),
+            used: state.used,
+            budget: self.budget,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_range() {
+        let cache = HotCache::new(1024 * 1024);
+        let a = CacheFileInfo::from_file_id(format!("abcd{}-4-jpg", "0".repeat(36))).unwrap();
+        let b = CacheFileInfo::from_file_id(format!("ef01{}-4-jpg", "0".repeat(36))).unwrap();
+        cache.insert(&a, Bytes::from_static(b"aaaa"));
+        cache.insert(&b, Bytes::from_static(b"bbbb"));
+
+        cache.remove_range("ABCD");
+        assert!(cache.get(&a).is_none());
+        assert!(cache.get(&b).is_some());
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 1);
+        assert_eq!(stats.used, 4);
+    }
+}
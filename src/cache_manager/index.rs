@@ -0,0 +1,171 @@
+use std::{
+    fs::{rename, File},
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use filetime::FileTime;
+
+use crate::cache_manager::{policy::SKETCH_SIZE, LRU_SIZE};
+
+const MAGIC: &[u8; 4] = b"HRCI";
+const VERSION: u32 = 2;
+/// Static ranges are 4 hex digits.
+const MAX_RANGES: usize = 65536;
+
+/// Snapshot of the cache state, used to skip the full directory walk at startup.
+pub(super) struct CacheIndex {
+    pub static_range: Vec<String>,
+    pub lru_clear_pos: usize,
+    pub lru_cache: Vec<u16>,
+    pub frequency: Vec<u8>,
+    pub dirs: Vec<DirRecord>,
+}
+
+pub(super) struct DirRecord {
+    /// Static range of the directory, e.g. "0a1b".
+    pub range: String,
+    /// Directory modification time when the record was taken.
+    pub mtime: FileTime,
+    /// Oldest file modification time in the directory.
+    pub oldest: FileTime,
+    /// Total real size of files in the directory.
+    pub size: u64,
+}
+
+impl CacheIndex {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, "Unknown cache index format"));
+        }
+
+        let range_count = read_u32(&mut reader)? as usize;
+        if range_count > MAX_RANGES {
+            return Err(invalid_len(range_count));
+        }
+        let mut static_range = Vec::with_capacity(range_count);
+        for _ in 0..range_count {
+            static_range.push(read_string(&mut reader)?);
+        }
+
+        let lru_clear_pos = read_u64(&mut reader)? as usize;
+        // Lengths are checked before allocation, a corrupted index must not abort startup
+        let lru_len = read_u64(&mut reader)? as usize;
+        if lru_len != 0 && lru_len != LRU_SIZE {
+            return Err(invalid_len(lru_len));
+        }
+        let mut lru_cache = vec![0; lru_len];
+        let mut buf = [0; 2];
+        for bit in lru_cache.iter_mut() {
+            reader.read_exact(&mut buf)?;
+            *bit = u16::from_le_bytes(buf);
+        }
+
+        let frequency_len = read_u64(&mut reader)? as usize;
+        if frequency_len != 0 && frequency_len != SKETCH_SIZE {
+            return Err(invalid_len(frequency_len));
+        }
+        let mut frequency = vec![0; frequency_len];
+        reader.read_exact(&mut frequency)?;
+
+        let dir_count = read_u64(&mut reader)? as usize;
+        if dir_count > MAX_RANGES {
+            return Err(invalid_len(dir_count));
+        }
+        let mut dirs = Vec::with_capacity(dir_count);
+        for _ in 0..dir_count {
+            dirs.push(DirRecord {
+                range: read_string(&mut reader)?,
+                mtime: read_time(&mut reader)?,
+                oldest: read_time(&mut reader)?,
+                size: read_u64(&mut reader)?,
+            });
+        }
+
+        Ok(Self {
+            static_range,
+            lru_clear_pos,
+            lru_cache,
+            frequency,
+            dirs,
+        })
+    }
+
+    /// Write index to a temp file then rename, so a crash never leaves a truncated index.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let temp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+
+        writer.write_all(&(self.static_range.len() as u32).to_le_bytes())?;
+        for range in &self.static_range {
+            write_string(&mut writer, range)?;
+        }
+
+        writer.write_all(&(self.lru_clear_pos as u64).to_le_bytes())?;
+        writer.write_all(&(self.lru_cache.len() as u64).to_le_bytes())?;
+        for bit in &self.lru_cache {
+            writer.write_all(&bit.to_le_bytes())?;
+        }
+
+        writer.write_all(&(self.frequency.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.frequency)?;
+
+        writer.write_all(&(self.dirs.len() as u64).to_le_bytes())?;
+        for dir in &self.dirs {
+            write_string(&mut writer, &dir.range)?;
+            write_time(&mut writer, dir.mtime)?;
+            write_time(&mut writer, dir.oldest)?;
+            writer.write_all(&dir.size.to_le_bytes())?;
+        }
+
+        writer.into_inner()?.sync_all()?;
+        rename(temp, path)
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn invalid_len(len: usize) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid length in cache index: {}", len))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, Error> {
+    let mut len = [0; 2];
+    reader.read_exact(&mut len)?;
+    let mut buf = vec![0; u16::from_le_bytes(len) as usize];
+    reader.read_exact(&mut buf)?;
+    String::from_utf8(buf).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+fn read_time<R: Read>(reader: &mut R) -> Result<FileTime, Error> {
+    let seconds = read_u64(reader)? as i64;
+    let nanos = read_u32(reader)?;
+    Ok(FileTime::from_unix_time(seconds, nanos))
+}
+
+fn write_string<W: Write>(writer: &mut W, str: &str) -> Result<(), Error> {
+    writer.write_all(&(str.len() as u16).to_le_bytes())?;
+    writer.write_all(str.as_bytes())
+}
+
+fn write_time<W: Write>(writer: &mut W, time: FileTime) -> Result<(), Error> {
+    writer.write_all(&time.unix_seconds().to_le_bytes())?;
+    writer.write_all(&time.nanoseconds().to_le_bytes())
+}
@@ -0,0 +1,160 @@
+use std::{
+    fs::{remove_file, File, OpenOptions},
+    io::{BufRead, BufReader, Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{error, warn};
+use parking_lot::Mutex;
+
+pub(super) const JOURNAL_FILE: &str = "import.journal";
+
+/// Write-ahead journal of imports in a cache root.
+///
+/// Every import is recorded before the file is moved into place and marked done after the directory is synced,
+/// so startup can remove files of imports interrupted by crash or power loss.
+pub(super) struct ImportJournal {
+    path: PathBuf,
+    state: Mutex<JournalState>,
+}
+
+#[derive(Default)]
+struct JournalState {
+    file: Option<File>,
+    /// Imports in progress, journal is truncated when it drops to 0.
+    pending: usize,
+}
+
+impl ImportJournal {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            path: root.join(JOURNAL_FILE),
+            state: Mutex::new(JournalState::default()),
+        }
+    }
+
+    /// Roll back unfinished imports, return the number of imports rolled back.
+    ///
+    /// Must run before the first import, `end` truncates the journal when nothing is in progress.
+    pub fn recover(&self) -> Result<usize, Error> {
+        let mut state = self.state.lock();
+        if state.pending > 0 {
+            return Ok(0); // Only safe before any import started
+        }
+        state.file = None;
+
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let mut unfinished = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match line.split_once(' ') {
+                Some(("BEGIN", path)) => unfinished.push(PathBuf::from(path)),
+                Some(("END", path)) => unfinished.retain(|p| p.as_os_str() != path),
+                _ => warn!("Invalid import journal record: {}", line), // Torn write
+            }
+        }
+
+        for path in &unfinished {
+            warn!("Rollback unfinished cache import: {:?}", path);
+            for path in [import_temp_path(path), path.clone()] {
+                if let Err(err) = remove_file(&path) {
+                    if err.kind() != ErrorKind::NotFound {
+                        error!("Rollback cache import error: path={:?}, err={}", path, err);
+                    }
+                }
+            }
+        }
+
+        remove_file(&self.path)?;
+        Ok(unfinished.len())
+    }
+
+    /// Record an import is starting, the record is synced before return.
+    pub fn begin(&self, path: &Path) -> Result<(), Error> {
+        let mut state = self.state.lock();
+        if state.file.is_none() {
+            state.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+            // Journal itself must survive crash
+            sync_dir(self.path.parent().unwrap())?;
+        }
+        let file = state.file.as_mut().unwrap();
+        file.write_all(format!("BEGIN {}\n", path.to_string_lossy()).as_bytes())?;
+        file.sync_data()?;
+        state.pending += 1;
+        Ok(())
+    }
+
+    /// Record an import is done or cleaned up after failure.
+    pub fn end(&self, path: &Path) {
+        let mut state = self.state.lock();
+        state.pending = state.pending.saturating_sub(1);
+        let pending = state.pending;
+        let Some(file) = state.file.as_mut() else {
+            return;
+        };
+
+        // Nothing in progress, start over to keep journal small.
+        let result = if pending == 0 {
+            file.set_len(0)
+        } else {
+            file.write_all(format!("END {}\n", path.to_string_lossy()).as_bytes())
+        };
+        let result = result.and_then(|_| file.sync_data());
+        if let Err(err) = result {
+            error!("Write import journal error: path={:?}, err={}", self.path, err);
+            state.file = None;
+        }
+    }
+}
+
+/// Temp file next to the final path, used when import can't be done with a rename.
+pub(super) fn import_temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+/// Make a rename or new entry in the directory durable.
+#[cfg(unix)]
+pub(super) fn sync_dir(dir: &Path) -> Result<(), Error> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+pub(super) fn sync_dir(_dir: &Path) -> Result<(), Error> {
+    Ok(()) // Directory can't be opened as a file, NTFS journal covers metadata.
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn rollback_unfinished_import() {
+        let dir = tempdir().unwrap();
+        let (done, unfinished) = (dir.path().join("done"), dir.path().join("unfinished"));
+        write(&done, b"done").unwrap();
+        write(&unfinished, b"unfinished").unwrap();
+
+        // Crash before the second import finished
+        let journal = ImportJournal::new(dir.path());
+        journal.begin(&done).unwrap();
+        journal.begin(&unfinished).unwrap();
+        journal.end(&done);
+        drop(journal);
+
+        let journal = ImportJournal::new(dir.path());
+        assert_eq!(journal.recover().unwrap(), 1);
+        assert!(done.exists());
+        assert!(!unfinished.exists());
+        assert!(!dir.path().join(JOURNAL_FILE).exists());
+    }
+}
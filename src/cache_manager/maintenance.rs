@@ -0,0 +1,702 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write,
+    io::Error,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Subcommand};
+use futures::{stream, StreamExt};
+use tempfile::TempPath;
+use tokio::{
+    fs::{copy, create_dir_all, metadata, read_dir, remove_file, rename},
+    task::spawn_blocking,
+};
+
+use crate::{
+    cache_manager::{
+        fs_store::{CacheRoot, FsStore},
+        index::CacheIndex,
+        journal::JOURNAL_FILE,
+        store::{CacheEntry, CacheFile, CacheStore},
+        CacheFileInfo, FileType, INDEX_FILE,
+    },
+    util::parse_size_arg,
+};
+
+/// Offline cache maintenance, works on a stopped client without login.
+#[derive(Args)]
+pub struct CacheToolArgs {
+    #[command(subcommand)]
+    command: CacheCommand,
+
+    /// Only report what would be changed, don't touch any file
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Print the result as JSON
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Show cache size and file count, with per-type and per-range breakdown
+    Stats,
+    /// Check SHA-1 hash of all cache files and remove corrupt files
+    Verify {
+        /// Number of static ranges checked at the same time
+        #[arg(long, default_value_t = 4)]
+        parallelism: usize,
+    },
+    /// Delete least recently used files until the cache fits in the size
+    Prune {
+        /// Target cache size, e.g. `500G`
+        #[arg(long, value_parser = parse_size_arg)]
+        to: u64,
+    },
+    /// Find stray files, files in wrong directories and size mismatches
+    CheckLayout,
+    /// Import loose cache files from another tree, e.g. Java client cache or a recovered disk
+    ///
+    /// Only files in the static ranges recorded by the last run of the client are imported.
+    Import {
+        /// Directory to search for cache files recursively
+        source: PathBuf,
+
+        /// Move files instead of copy, source files are removed after import
+        #[arg(long = "move")]
+        move_files: bool,
+    },
+}
+
+pub async fn run_cache_tool(args: CacheToolArgs, roots: Vec<CacheRoot>, data_dir: &Path, temp_dir: &Path) -> Result<(), Error> {
+    // Dry run and read only commands must not roll back unfinished imports
+    let modify = !args.dry_run;
+    let report = match args.command {
+        CacheCommand::Stats => stats(open_store(roots, false).await?).await?,
+        CacheCommand::Verify { parallelism } => verify(open_store(roots, modify).await?, parallelism.max(1), args.dry_run).await?,
+        CacheCommand::Prune { to } => prune(open_store(roots, modify).await?, to, args.dry_run).await?,
+        CacheCommand::CheckLayout => check_layout(&roots, args.dry_run).await?,
+        CacheCommand::Import { source, move_files } => {
+            let options = ImportOptions {
+                data_dir,
+                temp_dir,
+                move_files,
+                dry_run: args.dry_run,
+            };
+            import(open_store(roots, modify).await?, &source, options).await?
+        }
+    };
+
+    if args.json {
+        println!("{}", report.json);
+    } else {
+        print!("{}", report.text);
+    }
+    Ok(())
+}
+
+struct Report {
+    text: String,
+    json: String,
+}
+
+/// Sum of file count and size.
+#[derive(Default, Clone, Copy)]
+struct Usage {
+    files: u64,
+    size: u64,
+}
+
+impl Usage {
+    fn add(&mut self, size: u64) {
+        self.files += 1;
+        self.size += size;
+    }
+}
+
+async fn open_store(roots: Vec<CacheRoot>, recover: bool) -> Result<FsStore, Error> {
+    spawn_blocking(move || {
+        let store = FsStore::new(roots);
+        if recover {
+            store.recover();
+        }
+        store
+    })
+    .await
+    .map_err(Error::from)
+}
+
+async fn list_all(store: &FsStore) -> Result<Vec<CacheEntry>, Error> {
+    let mut ranges = store.list_ranges().await?;
+    ranges.sort_unstable();
+
+    let mut files = Vec::new();
+    for range in ranges {
+        files.extend(store.list_files(&range).await?);
+    }
+    Ok(files)
+}
+
+async fn stats(store: FsStore) -> Result<Report, Error> {
+    let mut total = Usage::default();
+    let mut types: BTreeMap<String, Usage> = BTreeMap::new();
+    let mut ranges: BTreeMap<String, Usage> = BTreeMap::new();
+    for file in list_all(&store).await? {
+        total.add(file.size);
+        types.entry(file.info.mime_type.to_string()).or_default().add(file.size);
+        ranges.entry(file.info.static_range()).or_default().add(file.size);
+    }
+
+    let mut text = format!("Total: {} files, {} bytes\n\nBy type:\n", total.files, total.size);
+    for (name, usage) in &types {
+        let _ = writeln!(text, "  {:<6} {:>10} files {:>16} bytes", name, usage.files, usage.size);
+    }
+    text.push_str("\nBy static range:\n");
+    for (name, usage) in &ranges {
+        let _ = writeln!(text, "  {:<6} {:>10} files {:>16} bytes", name, usage.files, usage.size);
+    }
+
+    let json = format!(
+        r#"{{"files":{},"size":{},"types":{},"ranges":{}}}"#,
+        total.files,
+        total.size,
+        usage_map_json(&types),
+        usage_map_json(&ranges)
+    );
+    Ok(Report { text, json })
+}
+
+async fn verify(store: FsStore, parallelism: usize, dry_run: bool) -> Result<Report, Error> {
+    let mut ranges = store.list_ranges().await?;
+    ranges.sort_unstable();
+
+    let store = &store;
+    let results: Vec<VerifyResult> = stream::iter(ranges)
+        .map(|range| async move {
+            let mut result = VerifyResult::default();
+            let files = match store.list_files(&range).await {
+                Ok(files) => files,
+                Err(err) => {
+                    result.errors.push((range, err.to_string()));
+                    return result;
+                }
+            };
+            for entry in files {
+                let Some(file) = store.get(&entry.info).await else {
+                    continue;
+                };
+                result.checked += 1;
+                match file.sha1().await {
+                    Ok(hash) if hash == entry.info.hash() => (),
+                    Ok(_) => {
+                        if !dry_run {
+                            if let Err(err) = store.remove(&entry.info).await {
+                                result
+                                    .errors
+                                    .push((entry.info.file_id(), format!("remove corrupt file failed: {}", err)));
+                                continue;
+                            }
+                        }
+                        result.corrupt.push(entry.info);
+                    }
+                    // Read failure doesn't mean the file is corrupt, keep it
+                    Err(err) => result.errors.push((entry.info.file_id(), err.to_string())),
+                }
+            }
+            result
+        })
+        .buffer_unordered(parallelism)
+        .collect()
+        .await;
+
+    let mut checked = 0;
+    let mut corrupt = Vec::new();
+    let mut errors = Vec::new();
+    for result in results {
+        checked += result.checked;
+        corrupt.extend(result.corrupt);
+        errors.extend(result.errors);
+    }
+    corrupt.sort_unstable_by_key(|info| info.file_id());
+    errors.sort_unstable();
+
+    let action = if dry_run { "Corrupt" } else { "Removed corrupt" };
+    let mut text = String::new();
+    for info in &corrupt {
+        let _ = writeln!(text, "{}: {}", action, info.file_id());
+    }
+    for (target, err) in &errors {
+        let _ = writeln!(text, "Error: {} ({})", target, err);
+    }
+    let _ = writeln!(
+        text,
+        "Checked {} files, {} corrupt, {} errors",
+        checked,
+        corrupt.len(),
+        errors.len()
+    );
+
+    let json = format!(
+        r#"{{"dry_run":{},"checked":{},"corrupt":[{}],"errors":[{}]}}"#,
+        dry_run,
+        checked,
+        corrupt
+            .iter()
+            .map(|info| json_string(&info.file_id()))
+            .collect::<Vec<_>>()
+            .join(","),
+        errors_json(&errors)
+    );
+    Ok(Report { text, json })
+}
+
+/// Verify result of a static range.
+#[derive(Default)]
+struct VerifyResult {
+    checked: u64,
+    corrupt: Vec<CacheFileInfo>,
+    /// File ID or static range failed with IO error, the files are kept.
+    errors: Vec<(String, String)>,
+}
+
+async fn prune(store: FsStore, target: u64, dry_run: bool) -> Result<Report, Error> {
+    let mut files = list_all(&store).await?;
+    files.sort_unstable_by(|a, b| a.mtime.cmp(&b.mtime));
+
+    let mut size: u64 = files.iter().map(|file| file.size).sum();
+    let before = size;
+    let mut removed = Vec::new();
+    let mut errors = Vec::new();
+    for file in files {
+        if size <= target {
+            break;
+        }
+        if !dry_run {
+            if let Err(err) = store.remove(&file.info).await {
+                errors.push((file.info.file_id(), err.to_string()));
+                continue;
+            }
+        }
+        size -= file.size;
+        removed.push(file.info);
+    }
+
+    let action = if dry_run { "Would remove" } else { "Removed" };
+    let mut text = String::new();
+    for info in &removed {
+        let _ = writeln!(text, "{}: {}", action, info.file_id());
+    }
+    for (file_id, err) in &errors {
+        let _ = writeln!(text, "Error: {} ({})", file_id, err);
+    }
+    let _ = writeln!(
+        text,
+        "{} {} files, {} errors, cache size {} -> {} bytes (target {} bytes)",
+        action,
+        removed.len(),
+        errors.len(),
+        before,
+        size,
+        target
+    );
+
+    let json = format!(
+        r#"{{"dry_run":{},"before":{},"after":{},"target":{},"removed":[{}],"errors":[{}]}}"#,
+        dry_run,
+        before,
+        size,
+        target,
+        removed
+            .iter()
+            .map(|info| json_string(&info.file_id()))
+            .collect::<Vec<_>>()
+            .join(","),
+        errors_json(&errors)
+    );
+    Ok(Report { text, json })
+}
+
+enum LayoutIssue {
+    /// File which is not a cache file, or placed outside of static range dirs.
+    StrayFile(PathBuf),
+    /// Directory which is not a static range dir, not removed automatically.
+    UnknownDir(PathBuf),
+    /// Cache file in the directory of another static range.
+    WrongDir {
+        path: PathBuf,
+        expected: PathBuf,
+    },
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    /// Static range also exists in another cache dir, not removed automatically.
+    DuplicateRange {
+        range: String,
+        path: PathBuf,
+    },
+}
+
+impl LayoutIssue {
+    fn kind(&self) -> &str {
+        match self {
+            LayoutIssue::StrayFile(_) => "stray_file",
+            LayoutIssue::UnknownDir(_) => "unknown_dir",
+            LayoutIssue::WrongDir { .. } => "wrong_dir",
+            LayoutIssue::SizeMismatch { .. } => "size_mismatch",
+            LayoutIssue::DuplicateRange { .. } => "duplicate_range",
+        }
+    }
+
+    fn path(&self) -> &Path {
+        match self {
+            LayoutIssue::StrayFile(path)
+            | LayoutIssue::UnknownDir(path)
+            | LayoutIssue::WrongDir { path, .. }
+            | LayoutIssue::SizeMismatch { path, .. }
+            | LayoutIssue::DuplicateRange { path, .. } => path,
+        }
+    }
+
+    fn detail(&self) -> String {
+        match self {
+            LayoutIssue::WrongDir { expected, .. } => format!("expected {}", expected.display()),
+            LayoutIssue::SizeMismatch { expected, actual, .. } => format!("expected {} bytes, got {} bytes", expected, actual),
+            LayoutIssue::DuplicateRange { range, .. } => format!("static range {}", range),
+            _ => String::new(),
+        }
+    }
+
+    /// Fix the issue, return false if the issue should be fixed manually.
+    async fn fix(&self) -> Result<bool, Error> {
+        match self {
+            LayoutIssue::StrayFile(path) | LayoutIssue::SizeMismatch { path, .. } => remove_file(path).await.map(|_| true),
+            LayoutIssue::WrongDir { path, expected } => {
+                if metadata(expected).await.is_ok() {
+                    // Already have one in the right place
+                    remove_file(path).await?;
+                } else {
+                    create_dir_all(expected.parent().unwrap()).await?;
+                    rename(path, expected).await?;
+                }
+                Ok(true)
+            }
+            LayoutIssue::UnknownDir(_) | LayoutIssue::DuplicateRange { .. } => Ok(false),
+        }
+    }
+}
+
+async fn check_layout(roots: &[CacheRoot], dry_run: bool) -> Result<Report, Error> {
+    let mut issues = Vec::new();
+    let mut seen: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for root in roots {
+        let mut l1_stream = read_dir(root.path()).await?;
+        while let Some(l1) = l1_stream.next_entry().await? {
+            let l1_path = l1.path();
+            let l1_name = l1.file_name().to_string_lossy().to_string();
+            if l1_name == JOURNAL_FILE {
+                continue;
+            }
+            if !l1.file_type().await?.is_dir() {
+                issues.push(LayoutIssue::StrayFile(l1_path));
+                continue;
+            }
+            if !is_hex_part(&l1_name) {
+                issues.push(LayoutIssue::UnknownDir(l1_path));
+                continue;
+            }
+
+            let mut l2_stream = read_dir(&l1_path).await?;
+            while let Some(l2) = l2_stream.next_entry().await? {
+                let l2_path = l2.path();
+                let l2_name = l2.file_name().to_string_lossy().to_string();
+                if !l2.file_type().await?.is_dir() {
+                    issues.push(LayoutIssue::StrayFile(l2_path));
+                    continue;
+                }
+                if !is_hex_part(&l2_name) {
+                    issues.push(LayoutIssue::UnknownDir(l2_path));
+                    continue;
+                }
+
+                let range = format!("{}{}", l1_name, l2_name);
+                if seen.contains_key(&range) {
+                    issues.push(LayoutIssue::DuplicateRange { range, path: l2_path });
+                    continue;
+                }
+                check_range_dir(root.path(), &range, &l2_path, &mut issues).await?;
+                seen.insert(range, l2_path);
+            }
+        }
+    }
+
+    let mut fixed = 0;
+    if !dry_run {
+        for issue in &issues {
+            if issue.fix().await? {
+                fixed += 1;
+            }
+        }
+    }
+
+    let mut text = String::new();
+    for issue in &issues {
+        let _ = write!(text, "{}: {}", issue.kind(), issue.path().display());
+        let _ = match issue.detail() {
+            detail if detail.is_empty() => writeln!(text),
+            detail => writeln!(text, " ({})", detail),
+        };
+    }
+    let _ = writeln!(text, "Found {} issues, fixed {}", issues.len(), fixed);
+
+    let json = format!(
+        r#"{{"dry_run":{},"fixed":{},"issues":[{}]}}"#,
+        dry_run,
+        fixed,
+        issues
+            .iter()
+            .map(|issue| format!(
+                r#"{{"kind":"{}","path":{},"detail":{}}}"#,
+                issue.kind(),
+                json_string(&issue.path().to_string_lossy()),
+                json_string(&issue.detail())
+            ))
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    Ok(Report { text, json })
+}
+
+async fn check_range_dir(root: &Path, range: &str, dir: &Path, issues: &mut Vec<LayoutIssue>) -> Result<(), Error> {
+    let mut stream = read_dir(dir).await?;
+    while let Some(entry) = stream.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        if metadata.is_dir() {
+            issues.push(LayoutIssue::UnknownDir(path));
+            continue;
+        }
+
+        let Some(info) = entry.file_name().to_str().and_then(CacheFileInfo::from_file_id) else {
+            issues.push(LayoutIssue::StrayFile(path));
+            continue;
+        };
+        if metadata.len() != info.size() as u64 {
+            issues.push(LayoutIssue::SizeMismatch {
+                path,
+                expected: info.size() as u64,
+                actual: metadata.len(),
+            });
+        } else if info.static_range() != range {
+            issues.push(LayoutIssue::WrongDir {
+                path,
+                expected: info.to_path(root),
+            });
+        }
+    }
+    Ok(())
+}
+
+struct ImportOptions<'a> {
+    data_dir: &'a Path,
+    temp_dir: &'a Path,
+    move_files: bool,
+    dry_run: bool,
+}
+
+enum ImportResult {
+    Imported(CacheFileInfo),
+    Rejected(String),
+    Skipped(String),
+}
+
+async fn import(store: FsStore, source: &Path, options: ImportOptions<'_>) -> Result<Report, Error> {
+    // Static range is only known after login, use the one recorded in cache index.
+    let index_path = options.data_dir.join(INDEX_FILE);
+    let index = spawn_blocking(move || CacheIndex::load(&index_path)).await?.map_err(|err| {
+        Error::new(
+            err.kind(),
+            format!(
+                "Can't read static ranges from cache index, start the client once before import: {}",
+                err
+            ),
+        )
+    })?;
+    let static_range: HashSet<String> = index.static_range.iter().map(|s| s.to_ascii_lowercase()).collect();
+    store.list_ranges().await?;
+
+    let mut results = Vec::new();
+    let mut dirs = vec![source.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut stream = read_dir(&dir).await?;
+        while let Some(entry) = stream.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            let result = import_file(&store, &static_range, &path, &options).await;
+            results.push((path, result));
+        }
+    }
+    results.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut imported = Vec::new();
+    let mut rejected = Vec::new();
+    let mut skipped = Vec::new();
+    let mut text = String::new();
+    for (path, result) in &results {
+        let path = path.to_string_lossy();
+        match result {
+            ImportResult::Imported(info) => {
+                let _ = writeln!(text, "imported: {} -> {}", path, info.file_id());
+                imported.push(format!(
+                    r#"{{"path":{},"file_id":{}}}"#,
+                    json_string(&path),
+                    json_string(&info.file_id())
+                ));
+            }
+            ImportResult::Rejected(reason) => {
+                let _ = writeln!(text, "rejected: {} ({})", path, reason);
+                rejected.push(format!(r#"{{"path":{},"reason":{}}}"#, json_string(&path), json_string(reason)));
+            }
+            ImportResult::Skipped(reason) => {
+                let _ = writeln!(text, "skipped: {} ({})", path, reason);
+                skipped.push(format!(r#"{{"path":{},"reason":{}}}"#, json_string(&path), json_string(reason)));
+            }
+        }
+    }
+    let _ = writeln!(
+        text,
+        "Imported {} files, rejected {}, skipped {}",
+        imported.len(),
+        rejected.len(),
+        skipped.len()
+    );
+
+    let json = format!(
+        r#"{{"dry_run":{},"imported":[{}],"rejected":[{}],"skipped":[{}]}}"#,
+        options.dry_run,
+        imported.join(","),
+        rejected.join(","),
+        skipped.join(",")
+    );
+    Ok(Report { text, json })
+}
+
+async fn import_file(store: &FsStore, static_range: &HashSet<String>, path: &Path, options: &ImportOptions<'_>) -> ImportResult {
+    let len = match metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(err) => return ImportResult::Rejected(err.to_string()),
+    };
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    let Some(info) = CacheFileInfo::from_file_id(name).or_else(|| derive_file_id(name, len)) else {
+        return ImportResult::Rejected("unknown file name".to_string());
+    };
+
+    if !static_range.contains(&info.static_range()) {
+        return ImportResult::Skipped(format!("static range {} not assigned", info.static_range()));
+    }
+    if store.get(&info).await.is_some() {
+        return ImportResult::Skipped("already cached".to_string());
+    }
+    if len != info.size() as u64 {
+        return ImportResult::Rejected(format!("size mismatch, expected {} bytes, got {} bytes", info.size(), len));
+    }
+    match CacheFile::Path(path.to_path_buf()).sha1().await {
+        Ok(hash) if hash == info.hash() => (),
+        Ok(_) => return ImportResult::Rejected("hash mismatch".to_string()),
+        Err(err) => return ImportResult::Rejected(err.to_string()),
+    }
+    if options.dry_run {
+        return ImportResult::Imported(info);
+    }
+
+    // Same as CacheManager::import_cache, the store moves a temp file into place.
+    let temp = if options.move_files {
+        TempPath::from_path(path)
+    } else {
+        match copy_to_temp(path, options.temp_dir).await {
+            Ok(temp) => temp,
+            Err(err) => return ImportResult::Rejected(err.to_string()),
+        }
+    };
+    match store.import(&info, &temp).await {
+        Ok(_) => ImportResult::Imported(info),
+        Err(err) => {
+            if options.move_files {
+                let _ = temp.keep(); // Never delete source on failure
+            }
+            ImportResult::Rejected(err.to_string())
+        }
+    }
+}
+
+/// Build file id for files named by hash only, e.g. `<sha1>.jpg`. Resolution is unknown, so it is left as 0.
+fn derive_file_id(name: &str, len: u64) -> Option<CacheFileInfo> {
+    let (hash, ext) = name.split_once('.').unwrap_or((name, ""));
+    let file_type = match ext.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => FileType::Jpeg,
+        "png" => FileType::Png,
+        "gif" => FileType::Gif,
+        "mp4" => FileType::Mp4,
+        "webm" | "wbm" => FileType::Webm,
+        "webp" | "wbp" => FileType::Webp,
+        "avif" | "avf" => FileType::Avif,
+        "jxl" => FileType::Jpegxl,
+        _ => return None,
+    };
+    if hash.len() != 40 || len > u32::MAX as u64 {
+        return None;
+    }
+    CacheFileInfo::from_file_id(format!("{}-{}-{}", hash.to_ascii_lowercase(), len, file_type))
+}
+
+async fn copy_to_temp(path: &Path, temp_dir: &Path) -> Result<TempPath, Error> {
+    let temp_dir = temp_dir.to_path_buf();
+    let temp = spawn_blocking(|| tempfile::Builder::new().prefix("importfile_").tempfile_in(temp_dir))
+        .await??
+        .into_temp_path();
+    copy(path, &temp).await?;
+    Ok(temp)
+}
+
+fn is_hex_part(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+}
+
+fn usage_map_json(map: &BTreeMap<String, Usage>) -> String {
+    let items: Vec<String> = map
+        .iter()
+        .map(|(name, usage)| format!(r#"{}:{{"files":{},"size":{}}}"#, json_string(name), usage.files, usage.size))
+        .collect();
+    format!("{{{}}}", items.join(","))
+}
+
+fn errors_json(errors: &[(String, String)]) -> String {
+    errors
+        .iter()
+        .map(|(target, err)| format!(r#"{{"target":{},"error":{}}}"#, json_string(target), json_string(err)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn json_string(str: &str) -> String {
+    let mut out = String::with_capacity(str.len() + 2);
+    out.push('"');
+    for c in str.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
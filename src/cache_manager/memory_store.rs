@@ -0,0 +1,156 @@
+use std::{collections::HashMap, io::Error};
+
+use axum::async_trait;
+use bytes::Bytes;
+use filetime::FileTime;
+use parking_lot::RwLock;
+use tempfile::TempPath;
+use tokio::fs::read;
+
+use crate::cache_manager::{
+    store::{CacheEntry, CacheFile, CacheStore, VolumeInfo},
+    CacheFileInfo,
+};
+
+type RangeFiles = HashMap<CacheFileInfo, (Bytes, FileTime)>;
+
+/// Keep all cache files in memory, nothing is persisted.
+#[derive(Default)]
+pub struct MemoryStore {
+    ranges: RwLock<HashMap<String, RangeFiles>>,
+}
+
+#[async_trait]
+impl CacheStore for MemoryStore {
+    async fn get(&self, info: &CacheFileInfo) -> Option<CacheFile> {
+        let ranges = self.ranges.read();
+        let (data, _) = ranges.get(&info.static_range())?.get(info)?;
+        Some(CacheFile::Bytes(data.clone()))
+    }
+
+    async fn import(&self, info: &CacheFileInfo, file: &TempPath) -> Result<u64, Error> {
+        let data = Bytes::from(read(file).await?);
+        let size = data.len() as u64;
+        self.ranges
+            .write()
+            .entry(info.static_range())
+            .or_default()
+            .insert(info.clone(), (data, FileTime::now()));
+        Ok(size)
+    }
+
+    async fn remove(&self, info: &CacheFileInfo) -> Result<Option<u64>, Error> {
+        let mut ranges = self.ranges.write();
+        Ok(ranges
+            .get_mut(&info.static_range())
+            .and_then(|files| files.remove(info))
+            .map(|(data, _)| data.len() as u64))
+    }
+
+    async fn touch(&self, info: &CacheFileInfo, cutoff: FileTime) {
+        let mut ranges = self.ranges.write();
+        if let Some((_, mtime)) = ranges.get_mut(&info.static_range()).and_then(|files| files.get_mut(info)) {
+            if *mtime < cutoff {
+                *mtime = FileTime::now();
+            }
+        }
+    }
+
+    async fn list_ranges(&self) -> Result<Vec<String>, Error> {
+        Ok(self.ranges.read().keys().cloned().collect())
+    }
+
+    async fn remove_range(&self, range: &str) -> Result<(), Error> {
+        self.ranges.write().remove(range);
+        Ok(())
+    }
+
+    async fn list_files(&self, range: &str) -> Result<Vec<CacheEntry>, Error> {
+        let ranges = self.ranges.read();
+        Ok(ranges
+            .get(range)
+            .map(|files| {
+                files
+                    .iter()
+                    .map(|(info, (data, mtime))| CacheEntry {
+                        info: info.clone(),
+                        len: data.len() as u64,
+                        size: data.len() as u64,
+                        mtime: *mtime,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn range_mtime(&self, _range: &str) -> Option<FileTime> {
+        None // Nothing survives restart, always rescan.
+    }
+
+    fn range_volume(&self, _range: &str) -> Option<usize> {
+        Some(0)
+    }
+
+    fn volumes(&self) -> Vec<VolumeInfo> {
+        vec![VolumeInfo {
+            name: "memory".to_string(),
+            size_limit: u64::MAX,
+            available_space: None,
+            healthy: true,
+        }]
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::*;
+    use crate::cache_manager::tests::test_file_info;
+
+    fn temp_file(data: &[u8]) -> TempPath {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        file.into_temp_path()
+    }
+
+    #[tokio::test]
+    async fn import_get_remove() {
+        let store = MemoryStore::default();
+        let data = b"memory store test";
+        let info = test_file_info(data);
+        assert!(store.get(&info).await.is_none());
+
+        assert_eq!(store.import(&info, &temp_file(data)).await.unwrap(), data.len() as u64);
+        match store.get(&info).await {
+            Some(CacheFile::Bytes(bytes)) => assert_eq!(&bytes[..], data),
+            _ => panic!("file not stored in memory"),
+        }
+        assert_eq!(store.list_ranges().await.unwrap(), vec![info.static_range()]);
+        let files = store.list_files(&info.static_range()).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].len, data.len() as u64);
+
+        assert_eq!(store.remove(&info).await.unwrap(), Some(data.len() as u64));
+        assert_eq!(store.remove(&info).await.unwrap(), None);
+        assert!(store.get(&info).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn remove_range() {
+        let store = MemoryStore::default();
+        let data = b"range test";
+        let info = test_file_info(data);
+        store.import(&info, &temp_file(data)).await.unwrap();
+
+        store.remove_range(&info.static_range()).await.unwrap();
+        assert!(store.get(&info).await.is_none());
+        assert!(store.list_ranges().await.unwrap().is_empty());
+    }
+}
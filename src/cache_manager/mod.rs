@@ -0,0 +1,1146 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::Error,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use filetime::FileTime;
+use futures::{stream, StreamExt};
+use hex::FromHex;
+use log::{debug, error, info, warn};
+use mime::Mime;
+use parking_lot::{Mutex, RwLock};
+use rand::{seq::SliceRandom, thread_rng, Rng};
+use tempfile::TempPath;
+use tokio::{
+    fs::{metadata, read, read_dir, remove_file},
+    select, spawn,
+    sync::{mpsc::UnboundedSender, Notify},
+    task::spawn_blocking,
+    time::{sleep_until, Instant},
+};
+
+pub use crate::cache_manager::{
+    fs_store::{CacheRoot, FsStore},
+    hot_cache::HotCacheStats,
+    maintenance::{run_cache_tool, CacheToolArgs},
+    memory_store::MemoryStore,
+    policy::EvictionPolicy,
+    store::{CacheFile, CacheStore},
+};
+use crate::{
+    cache_manager::{
+        admission::Admission,
+        hot_cache::HotCache,
+        index::{CacheIndex, DirRecord},
+        policy::FrequencySketch,
+        verifier::{Verifier, VERIFY_PROGRESS_FILE},
+    },
+    rpc::{InitSettings, Settings},
+};
+
+mod admission;
+mod fs_store;
+mod hot_cache;
+mod index;
+mod journal;
+mod maintenance;
+mod memory_store;
+mod policy;
+mod store;
+mod verifier;
+
+const INDEX_FILE: &str = "cache_index";
+pub(super) const LRU_SIZE: usize = 1048576; // u16 * LRU_SIZE = 2MiB
+const LFU_SAMPLE_RANGES: usize = 32;
+const HOT_MIN_FREQUENCY: u8 = 2; // Only keep files requested again in RAM
+
+pub struct CacheOptions {
+    pub policy: EvictionPolicy,
+    /// Usage percent which eviction frees space down to.
+    pub low_watermark: u8,
+    /// Usage percent which starts eviction and stops storing new files.
+    pub high_watermark: u8,
+    /// Byte budget of the in-memory hot object tier, 0 to disable.
+    pub hot_cache_size: u64,
+    /// IO budget of the background verifier in bytes/sec, 0 to disable.
+    pub verify_rate: u64,
+}
+
+pub struct CacheManager {
+    store: Arc<dyn CacheStore>,
+    admission: Admission,
+    cache_date: Mutex<HashMap<String, FileTime>>,
+    cache_size: Mutex<HashMap<String, u64>>,
+    frequency: FrequencySketch,
+    hot_cache: HotCache,
+    index_path: PathBuf,
+    index_ready: AtomicBool,
+    index_notify: Notify,
+    lru_cache: RwLock<Vec<u16>>,
+    lru_clear_pos: Mutex<usize>,
+    policy: EvictionPolicy,
+    /// Whether a background purge list cross-check is running.
+    purge_checking: AtomicBool,
+    static_range: RwLock<Vec<String>>,
+    /// Static ranges no longer assigned, waiting for removal.
+    stale_ranges: Mutex<Vec<String>>,
+    temp_dir: PathBuf,
+    total_size: Arc<AtomicU64>,
+    size_limit: AtomicU64,
+    verifier: Verifier,
+}
+
+impl CacheManager {
+    pub async fn new<P: AsRef<Path>>(
+        store: Arc<dyn CacheStore>,
+        temp_dir: P,
+        data_dir: P,
+        settings: Arc<Settings>,
+        init_settings: &InitSettings,
+        options: CacheOptions,
+        shutdown: UnboundedSender<()>,
+    ) -> Result<Arc<Self>, Error> {
+        let verify_cache = init_settings.verify_cache();
+        let static_range = init_settings.static_range();
+        let new = Arc::new(Self {
+            store,
+            admission: Admission::new(options.low_watermark, options.high_watermark),
+            cache_date: Mutex::new(HashMap::with_capacity(6000)),
+            cache_size: Mutex::new(HashMap::with_capacity(6000)),
+            frequency: FrequencySketch::default(),
+            hot_cache: HotCache::new(options.hot_cache_size),
+            index_path: data_dir.as_ref().join(INDEX_FILE),
+            index_ready: AtomicBool::new(false),
+            index_notify: Notify::new(),
+            lru_cache: RwLock::new(vec![0; LRU_SIZE]),
+            lru_clear_pos: Mutex::new(thread_rng().gen_range(0..LRU_SIZE)),
+            policy: options.policy,
+            purge_checking: AtomicBool::new(false),
+            static_range: RwLock::new(static_range.clone()),
+            stale_ranges: Mutex::new(Vec::new()),
+            temp_dir: temp_dir.as_ref().to_path_buf(),
+            total_size: Arc::new(AtomicU64::new(0)),
+            size_limit: AtomicU64::new(u64::MAX),
+            verifier: Verifier::new(options.verify_rate, data_dir.as_ref().join(VERIFY_PROGRESS_FILE)),
+        });
+        new.update_settings(settings);
+
+        clean_temp_dir(temp_dir.as_ref()).await;
+
+        let manager = new.clone();
+        if verify_cache {
+            // Force check cache
+            info!("Start force cache check");
+            new.scan_cache(static_range, 16, verify_cache).await?;
+            new.save_index().await;
+            CacheManager::start_background_task(manager);
+        } else if let Some(index) = new.load_index().await {
+            // Check index against cache dirs in background
+            info!("Start background cache index check");
+            let recorded = new.apply_index(index);
+            spawn(async move {
+                if let Err(err) = manager.check_index(static_range, recorded, 4).await {
+                    error!("Cache index check error: {}", err);
+                    let _ = shutdown.send(());
+                }
+                manager.save_index().await;
+                CacheManager::start_background_task(manager);
+            });
+        } else {
+            // Background cache scan
+            info!("Start background cache scan");
+            spawn(async move {
+                if let Err(err) = manager.scan_cache(static_range, 4, verify_cache).await {
+                    error!("Cache scan error: {}", err);
+                    let _ = shutdown.send(());
+                }
+                manager.save_index().await;
+                CacheManager::start_background_task(manager);
+            });
+        }
+
+        Ok(new)
+    }
+
+    pub async fn create_temp_file(&self) -> TempPath {
+        let temp_dir = self.temp_dir.clone();
+        spawn_blocking(|| {
+            tempfile::Builder::new()
+                .prefix("proxyfile_")
+                .tempfile_in(temp_dir)
+                .unwrap()
+                .into_temp_path()
+        })
+        .await
+        .unwrap()
+    }
+
+    pub async fn get_file(&self, info: &CacheFileInfo) -> Option<CacheFile> {
+        self.frequency.increment(&info.hash());
+
+        if self.hot_cache.is_enabled() {
+            if let Some(data) = self.hot_cache.get(info) {
+                self.mark_recently_accessed(info, true).await;
+                return Some(CacheFile::Bytes(data));
+            }
+        }
+
+        let mut file = self.store.get(info).await;
+        if let Some(CacheFile::Path(path)) = &file {
+            if self.hot_cache.admissible(info.size() as u64) && self.frequency.estimate(&info.hash()) >= HOT_MIN_FREQUENCY {
+                match read(path).await {
+                    Ok(data) if data.len() == info.size() as usize => {
+                        let data = Bytes::from(data);
+                        self.hot_cache.insert(info, data.clone());
+                        file = Some(CacheFile::Bytes(data));
+                    }
+                    Ok(_) => (),
+                    Err(err) => warn!("Load file to hot cache error: path={:?}, err={}", path, err),
+                }
+            }
+        }
+        if file.is_some() {
+            self.mark_recently_accessed(info, true).await;
+        }
+
+        file
+    }
+
+    pub fn hot_cache_stats(&self) -> Option<HotCacheStats> {
+        self.hot_cache.is_enabled().then(|| self.hot_cache.stats())
+    }
+
+    pub async fn import_cache(&self, info: &CacheFileInfo, file_path: &TempPath) {
+        // Try remove existing file
+        self.remove_cache(info).await;
+
+        // Importing
+        let size = match self.store.import(info, file_path).await {
+            Ok(size) => size,
+            Err(err) => {
+                error!("Import cache failed: {}", err);
+                return;
+            }
+        };
+
+        self.mark_recently_accessed(info, false).await;
+
+        let range = info.static_range();
+        self.cache_date.lock().entry(range.clone()).or_insert_with(FileTime::now);
+        *self.cache_size.lock().entry(range).or_default() += size;
+        self.total_size.fetch_add(size, Relaxed);
+
+        self.check_pressure(info);
+    }
+
+    pub async fn remove_cache(&self, info: &CacheFileInfo) {
+        self.hot_cache.remove(info);
+        match self.store.remove(info).await {
+            Ok(Some(size)) => {
+                self.total_size.fetch_sub(size, Relaxed);
+                if let Some(range_size) = self.cache_size.lock().get_mut(&info.static_range()) {
+                    *range_size = range_size.saturating_sub(size);
+                }
+            }
+            Ok(None) => (),
+            Err(err) => error!("Delete cache file error: file={}, err={}", info.file_id(), err),
+        }
+    }
+
+    /// Remove purged files from cache.
+    ///
+    /// With `full_check`, every cached file is checked against the list instead of looking up each listed file,
+    /// which is cheaper for the long list after a long downtime. It waits for the startup cache scan,
+    /// and returns false if any static range can't be checked.
+    pub async fn purge(&self, list: &[String], full_check: bool) -> bool {
+        let files = list.iter().filter_map(CacheFileInfo::from_file_id);
+        if !full_check {
+            for info in files {
+                self.remove_cache(&info).await;
+            }
+            return true;
+        }
+
+        // Don't compete with the startup cache scan
+        self.wait_index_ready().await;
+
+        let purged: HashSet<[u8; 20]> = files.map(|info| info.hash()).collect();
+        let ranges = self.static_range.read().clone();
+        let mut removed = 0;
+        let mut success = true;
+        for range in ranges {
+            let files = match self.store.list_files(&range).await {
+                Ok(files) => files,
+                Err(err) => {
+                    error!("Read cache dir {} error: {}", range, err);
+                    success = false;
+                    continue;
+                }
+            };
+            for file in files.into_iter().filter(|f| purged.contains(&f.info.hash())) {
+                self.remove_cache(&file.info).await;
+                removed += 1;
+            }
+        }
+        info!(
+            "Finished purge list cross-check: purged={}, removed={}, success={}",
+            purged.len(),
+            removed,
+            success
+        );
+        success
+    }
+
+    /// Mark a purge list cross-check as running, return false if one is already running.
+    pub fn start_purge_check(&self) -> bool {
+        !self.purge_checking.swap(true, Relaxed)
+    }
+
+    pub fn finish_purge_check(&self) {
+        self.purge_checking.store(false, Relaxed);
+    }
+
+    fn set_index_ready(&self) {
+        self.index_ready.store(true, Relaxed);
+        self.index_notify.notify_waiters();
+    }
+
+    /// Wait until startup cache scan or index check finished.
+    async fn wait_index_ready(&self) {
+        let notified = self.index_notify.notified();
+        if self.index_ready.load(Relaxed) {
+            return;
+        }
+        notified.await;
+    }
+
+    /// Save the cache index to data dir, so next startup can skip the full cache scan.
+    pub async fn save_index(&self) {
+        if !self.index_ready.load(Relaxed) {
+            return; // Cache scan not finished
+        }
+
+        // Read range mtime before taking size snapshot, any change after here will invalidate the record.
+        let ranges: Vec<String> = self.cache_date.lock().keys().cloned().collect();
+        let mut mtimes = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            let mtime = self
+                .store
+                .range_mtime(&range)
+                .await
+                .unwrap_or_else(|| FileTime::from_unix_time(0, 0));
+            mtimes.push((range, mtime));
+        }
+
+        let records = {
+            let cache_date = self.cache_date.lock();
+            let cache_size = self.cache_size.lock();
+            mtimes
+                .into_iter()
+                .filter_map(|(range, mtime)| {
+                    Some(DirRecord {
+                        oldest: *cache_date.get(&range)?,
+                        size: cache_size.get(&range).copied().unwrap_or_default(),
+                        range,
+                        mtime,
+                    })
+                })
+                .collect()
+        };
+        let index = CacheIndex {
+            static_range: self.static_range.read().clone(),
+            lru_clear_pos: *self.lru_clear_pos.lock(),
+            lru_cache: self.lru_cache.read().clone(),
+            frequency: self.frequency.to_vec(),
+            dirs: records,
+        };
+
+        let path = self.index_path.clone();
+        match spawn_blocking(move || index.save(&path)).await {
+            Ok(Ok(_)) => debug!("Saved cache index"),
+            Ok(Err(err)) => error!("Save cache index error: {}", err),
+            Err(_) => (),
+        }
+    }
+
+    pub fn update_settings(&self, settings: Arc<Settings>) {
+        let size_limit = settings.size_limit();
+        info!("Set size limit to {:} GiB", size_limit / 1024 / 1024 / 1024);
+        self.size_limit.store(size_limit, Relaxed);
+
+        let disable_lru = settings.disable_lru_cache();
+        let disabled = self.lru_cache.read().is_empty();
+        if disable_lru && !disabled {
+            info!("Disable LRU cache");
+            let mut cache = self.lru_cache.write();
+            cache.clear();
+            cache.shrink_to_fit();
+        } else if !disable_lru && disabled {
+            info!("Enable LRU cache");
+            self.lru_cache.write().resize(LRU_SIZE, 0);
+        }
+
+        self.update_static_range(settings.static_range());
+    }
+
+    /// Track static range assignment, removed ranges are deleted by background task.
+    fn update_static_range(&self, new_range: Vec<String>) {
+        if new_range.is_empty() {
+            return; // Not included in settings
+        }
+
+        let new_set: HashSet<String> = new_range.iter().map(|s| s.to_ascii_lowercase()).collect();
+        let mut static_range = self.static_range.write();
+        let old_set: HashSet<String> = static_range.iter().map(|s| s.to_ascii_lowercase()).collect();
+        if new_set == old_set {
+            return;
+        }
+
+        let added = new_set.difference(&old_set).count();
+        let removed: Vec<String> = old_set.difference(&new_set).cloned().collect();
+        info!("Static range changed: added={}, removed={}", added, removed.len());
+        *static_range = new_range;
+
+        let mut stale_ranges = self.stale_ranges.lock();
+        stale_ranges.retain(|range| !new_set.contains(range)); // Assigned again before removal
+        stale_ranges.extend(removed);
+    }
+
+    /// Delete static ranges which no longer assigned and reclaim the space.
+    async fn remove_stale_ranges(&self) {
+        let ranges = std::mem::take(&mut *self.stale_ranges.lock());
+        if ranges.is_empty() {
+            return;
+        }
+
+        for range in ranges {
+            // Assigned again while waiting
+            if self.static_range.read().iter().any(|sr| range.eq_ignore_ascii_case(sr)) {
+                continue;
+            }
+
+            info!("Delete static range no longer assigned: {}", range);
+            self.hot_cache.remove_range(&range);
+            if let Err(err) = self.store.remove_range(&range).await {
+                error!("Delete static range {} error: {}", range, err);
+            }
+            self.cache_date.lock().remove(&range);
+            if let Some(size) = self.cache_size.lock().remove(&range) {
+                self.total_size.fetch_sub(size, Relaxed);
+            }
+        }
+        self.save_index().await;
+    }
+
+    fn start_background_task(new: Arc<Self>) {
+        CacheManager::start_verifier(new.clone());
+
+        let evict_notify = new.admission.evict_notify.clone();
+        let manager = Arc::downgrade(&new);
+        spawn(async move {
+            let mut counter: u32 = 0;
+            let mut next_run = Instant::now();
+            loop {
+                let evict_now = select! {
+                    _ = sleep_until(next_run) => false,
+                    _ = evict_notify.notified() => true,
+                };
+                if let Some(manager) = manager.upgrade() {
+                    // Usage crossed high watermark
+                    if evict_now {
+                        manager.check_cache_usage().await;
+                        continue;
+                    }
+
+                    // Cycle LRU cache
+                    manager.cycle_lru_cache();
+                    manager.remove_stale_ranges().await;
+                    // Check cache size every 10min
+                    if counter % 60 == 0 {
+                        manager.check_cache_usage().await;
+                        manager.log_admission_stats();
+                        manager.log_hot_cache_stats();
+                        manager.log_verify_stats();
+                    }
+                    // Save cache index every 1hr
+                    if counter % 360 == 359 {
+                        manager.save_index().await;
+                    }
+
+                    counter = counter.wrapping_add(1);
+                    next_run = Instant::now() + Duration::from_secs(10);
+                } else {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn log_hot_cache_stats(&self) {
+        if let Some(stats) = self.hot_cache_stats() {
+            let total = stats.hits + stats.misses;
+            info!(
+                "Hot cache: hits={}, misses={}, hit_rate={:.1}%, files={}, used={}/{}bytes",
+                stats.hits,
+                stats.misses,
+                if total == 0 {
+                    0.0
+                } else {
+                    stats.hits as f64 * 100.0 / total as f64
+                },
+                stats.entries,
+                stats.used,
+                stats.budget
+            );
+        }
+    }
+
+    async fn mark_recently_accessed(&self, info: &CacheFileInfo, update_file: bool) {
+        let hash = info.hash();
+        let index = (u32::from_be_bytes([0, hash[2], hash[3], hash[4]]) >> 4) as usize;
+        let bitmask: u16 = 1 << (hash[4] & 0b0000_1111);
+
+        // Check if the file is already in the cache.
+        if self.lru_cache.read().get(index).map(|bit| bit & bitmask != 0).unwrap_or(false) {
+            // Already marked, return
+            return;
+        }
+
+        // Mark the file as recently accessed.
+        if let Some(bit) = self.lru_cache.write().get_mut(index) {
+            *bit |= bitmask;
+        }
+
+        if update_file {
+            let one_week_ago = SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 7);
+            self.store.touch(info, one_week_ago.into()).await;
+        }
+    }
+
+    async fn scan_cache(&self, static_range: Vec<String>, parallelism: usize, verify_cache: bool) -> Result<(), Error> {
+        let ranges = self.list_ranges(&static_range).await?;
+
+        debug!("Cache dir number: {}", &ranges.len());
+
+        let lru_cutoff = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 7)); // 1 week
+        let counter = &AtomicUsize::new(0);
+        let total = ranges.len();
+        let scan_task = stream::iter(ranges)
+            .map(|range| async move {
+                let (time, size) = self.scan_range(&range, lru_cutoff, verify_cache).await?;
+                self.total_size.fetch_add(size, Relaxed);
+
+                let count = counter.fetch_add(1, Relaxed) + 1;
+                if count % 100 == 0 || count == total {
+                    info!("Scanned {}/{} static ranges.", count, total);
+                }
+
+                Ok::<(String, FileTime, u64), Error>((range, time, size))
+            })
+            .buffer_unordered(parallelism)
+            .collect::<Vec<_>>()
+            .await;
+
+        if self.store.is_persistent() && counter.load(Relaxed) == 0 && static_range.len() > 20 {
+            error!(
+                "This client has static ranges assigned to it, but the cache is empty. Check file permissions and file system integrity."
+            );
+            return Err(Error::new(std::io::ErrorKind::NotFound, "Cache is empty."));
+        }
+
+        // Save oldest mtime
+        let mut map = self.cache_date.lock();
+        let mut size_map = self.cache_size.lock();
+        map.clear();
+        size_map.clear();
+        for task in scan_task {
+            match task {
+                Ok((range, time, size)) => {
+                    map.insert(range.clone(), time);
+                    size_map.insert(range, size);
+                }
+                Err(err) => error!("Scan cache dir error: {}", err),
+            }
+        }
+        self.set_index_ready();
+
+        info!("Finished cache scan. Cache size: {}", self.total_size.load(Relaxed));
+
+        Ok(())
+    }
+
+    /// List all static ranges in store, delete ranges which not assigned to this client.
+    async fn list_ranges(&self, static_range: &[String]) -> Result<Vec<String>, Error> {
+        let mut ranges = self.store.list_ranges().await?;
+        let mut stale = Vec::new();
+        ranges.retain(|range| {
+            let keep = static_range.iter().any(|sr| range.eq_ignore_ascii_case(sr));
+            if !keep {
+                stale.push(range.clone());
+            }
+            keep
+        });
+
+        for range in stale {
+            warn!("Delete not in static range dir: {}", range);
+            if let Err(err) = self.store.remove_range(&range).await {
+                error!("Delete cache dir error: range={}, err={}", range, err);
+            }
+        }
+
+        Ok(ranges)
+    }
+
+    /// Scan a static range, return the oldest mtime and total size of files.
+    async fn scan_range(&self, range: &str, lru_cutoff: FileTime, verify_cache: bool) -> Result<(FileTime, u64), Error> {
+        let mut time = FileTime::now();
+        let mut size_sum = 0;
+        for entry in self.store.list_files(range).await? {
+            let info = entry.info;
+            if entry.len != info.size() as u64 {
+                warn!(
+                    "Delete corrupt cache file: file={}, size={:x?}, actual={:x?}",
+                    info.file_id(),
+                    &info.size(),
+                    entry.len
+                );
+
+                if let Err(err) = self.store.remove(&info).await {
+                    error!("Delete corrupt cache file error: file={}, err={}", info.file_id(), err);
+                }
+                continue;
+            }
+
+            if verify_cache {
+                let actual_hash = match self.store.get(&info).await {
+                    Some(file) => file.sha1().await.unwrap_or_else(|err| {
+                        error!("Read cache file {} error: {}", info.file_id(), err);
+                        [0; 20]
+                    }),
+                    None => continue,
+                };
+                if actual_hash != info.hash {
+                    warn!(
+                        "Delete corrupt cache file: file={}, hash={:x?}, actual={:x?}",
+                        info.file_id(),
+                        &info.hash,
+                        &actual_hash
+                    );
+                    if let Err(err) = self.store.remove(&info).await {
+                        error!("Delete corrupt cache file error: file={}, err={}", info.file_id(), err);
+                    }
+                    continue;
+                }
+            }
+
+            size_sum += entry.size;
+
+            // Add recently accessed file to the cache.
+            if entry.mtime > lru_cutoff {
+                self.mark_recently_accessed(&info, false).await;
+            }
+            if entry.mtime < time {
+                time = entry.mtime;
+            }
+        }
+
+        Ok((time, size_sum))
+    }
+
+    async fn load_index(&self) -> Option<CacheIndex> {
+        let path = self.index_path.clone();
+        let index = match spawn_blocking(move || CacheIndex::load(&path)).await.ok()? {
+            Ok(index) => index,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Load cache index error: {}", err);
+                }
+                return None;
+            }
+        };
+
+        // Static range changed, index is stale.
+        let recorded: HashSet<String> = index.static_range.iter().map(|s| s.to_ascii_lowercase()).collect();
+        let current: HashSet<String> = self.static_range.read().iter().map(|s| s.to_ascii_lowercase()).collect();
+        if recorded != current {
+            info!("Static range changed, ignore cache index");
+            return None;
+        }
+
+        Some(index)
+    }
+
+    /// Fill cache state from index, return the recorded range mtime for index check.
+    fn apply_index(&self, index: CacheIndex) -> HashMap<String, FileTime> {
+        let mut recorded = HashMap::with_capacity(index.dirs.len());
+        let mut cache_date = self.cache_date.lock();
+        let mut cache_size = self.cache_size.lock();
+        let mut total_size = 0;
+        for dir in index.dirs {
+            total_size += dir.size;
+            cache_date.insert(dir.range.clone(), dir.oldest);
+            cache_size.insert(dir.range.clone(), dir.size);
+            recorded.insert(dir.range, dir.mtime);
+        }
+        self.total_size.store(total_size, Relaxed);
+
+        // Restore LRU cache if enabled
+        let mut lru_cache = self.lru_cache.write();
+        if !lru_cache.is_empty() && index.lru_cache.len() == lru_cache.len() {
+            *lru_cache = index.lru_cache;
+            *self.lru_clear_pos.lock() = index.lru_clear_pos % LRU_SIZE;
+        }
+        self.frequency.restore(&index.frequency);
+
+        info!("Loaded cache index. Cache size: {}", total_size);
+        recorded
+    }
+
+    /// Rescan static ranges which modified after index saved.
+    async fn check_index(&self, static_range: Vec<String>, recorded: HashMap<String, FileTime>, parallelism: usize) -> Result<(), Error> {
+        let ranges = self.list_ranges(&static_range).await?;
+
+        // Remove missing ranges
+        {
+            let exists: HashSet<&String> = ranges.iter().collect();
+            let mut cache_date = self.cache_date.lock();
+            let mut cache_size = self.cache_size.lock();
+            cache_date.retain(|range, _| exists.contains(range));
+            cache_size.retain(|range, size| {
+                let keep = exists.contains(range);
+                if !keep {
+                    self.total_size.fetch_sub(*size, Relaxed);
+                }
+                keep
+            });
+        }
+
+        let lru_cutoff = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(60 * 60 * 24 * 7)); // 1 week
+        let recorded = &recorded;
+        let counter = &AtomicUsize::new(0);
+        stream::iter(ranges)
+            .for_each_concurrent(parallelism, |range| async move {
+                let mtime = self.store.range_mtime(&range).await;
+                if mtime.is_some() && recorded.get(&range) == mtime.as_ref() {
+                    return;
+                }
+
+                debug!("Rescan modified static range: {}", range);
+                match self.scan_range(&range, lru_cutoff, false).await {
+                    Ok((time, size)) => {
+                        self.cache_date.lock().insert(range.clone(), time);
+                        if let Some(old) = self.cache_size.lock().insert(range, size) {
+                            self.total_size.fetch_sub(old, Relaxed);
+                        }
+                        self.total_size.fetch_add(size, Relaxed);
+                        counter.fetch_add(1, Relaxed);
+                    }
+                    Err(err) => error!("Scan cache dir error: {}", err),
+                }
+            })
+            .await;
+        self.set_index_ready();
+
+        info!(
+            "Finished cache index check. Rescanned {} static ranges. Cache size: {}",
+            counter.load(Relaxed),
+            self.total_size.load(Relaxed)
+        );
+
+        Ok(())
+    }
+
+    fn cycle_lru_cache(&self) {
+        let mut pos = self.lru_clear_pos.lock();
+        // 1048576 / (1week / 10s) =~ 17
+        for _ in 0..17 {
+            if let Some(bit) = self.lru_cache.write().get_mut(*pos) {
+                *bit = 0;
+                *pos = (*pos + 1) % LRU_SIZE;
+            }
+        }
+    }
+
+    /// Size of cache files in each volume.
+    pub(super) fn volume_usage(&self) -> Vec<u64> {
+        let mut usage = Vec::new();
+        for (range, size) in self.cache_size.lock().iter() {
+            if let Some(index) = self.store.range_volume(range) {
+                if usage.len() <= index {
+                    usage.resize(index + 1, 0);
+                }
+                usage[index] += size;
+            }
+        }
+        usage
+    }
+
+    /// Delete files until freed enough space, only delete files in the volume if specified.
+    pub(super) async fn free_cache(&self, need_free: u64, volume: Option<usize>) {
+        debug!(
+            "Start cache cleaner: need_free={}bytes, volume={:?}, policy={:?}",
+            need_free, volume, self.policy
+        );
+        match self.policy {
+            EvictionPolicy::OldestDir => self.free_oldest_dir(need_free, volume).await,
+            EvictionPolicy::Lfu => self.free_lfu(need_free, volume).await,
+        }
+    }
+
+    /// Delete oldest files from the static range with the oldest modification time.
+    async fn free_oldest_dir(&self, mut need_free: u64, volume: Option<usize>) {
+        let mut idle = 0;
+        while need_free > 0 {
+            let target;
+            let cut_off;
+            let candidates;
+            {
+                let map = self.cache_date.lock();
+                let mut ranges = map
+                    .iter()
+                    .filter(|(range, _)| volume.is_none() || self.store.range_volume(range) == volume)
+                    .collect::<Vec<_>>();
+                if ranges.is_empty() {
+                    return;
+                }
+                ranges.sort_unstable_by(|(_, a), (_, b)| a.cmp(b));
+                target = ranges[0].0.clone();
+                cut_off = ranges.get(1).map(|(_, t)| **t).unwrap_or_else(FileTime::now);
+                candidates = ranges.len();
+            }
+
+            let mut files = match self.store.list_files(&target).await {
+                Ok(files) => files,
+                Err(err) => {
+                    error!("Read cache dir {} error: {}", target, err);
+                    break;
+                }
+            };
+            files.sort_unstable_by(|a, b| a.mtime.cmp(&b.mtime));
+
+            let mut new_oldest = cut_off;
+            let mut removed = false;
+            for file in files {
+                if file.mtime > cut_off || need_free == 0 {
+                    new_oldest = file.mtime;
+                    break;
+                }
+
+                self.remove_cache(&file.info).await;
+
+                need_free = need_free.saturating_sub(file.size);
+                removed = true;
+            }
+
+            self.cache_date.lock().insert(target, new_oldest);
+
+            // Nothing left to delete
+            idle = if removed { 0 } else { idle + 1 };
+            if idle > candidates {
+                warn!(
+                    "Cache cleaner can't free enough space: need_free={}bytes, volume={:?}",
+                    need_free, volume
+                );
+                break;
+            }
+        }
+    }
+
+    /// Delete least frequently accessed files from randomly sampled static ranges, older files go first on tie.
+    async fn free_lfu(&self, mut need_free: u64, volume: Option<usize>) {
+        let mut idle = 0;
+        while need_free > 0 {
+            let targets: Vec<String> = {
+                let map = self.cache_date.lock();
+                let ranges = map
+                    .keys()
+                    .filter(|range| volume.is_none() || self.store.range_volume(range) == volume)
+                    .collect::<Vec<_>>();
+                if ranges.is_empty() {
+                    return;
+                }
+                ranges
+                    .choose_multiple(&mut thread_rng(), LFU_SAMPLE_RANGES)
+                    .map(|s| s.to_string())
+                    .collect()
+            };
+
+            let mut files = Vec::new();
+            for range in &targets {
+                match self.store.list_files(range).await {
+                    Ok(list) => files.extend(list),
+                    Err(err) => error!("Read cache dir {} error: {}", range, err),
+                }
+            }
+            files.sort_by_cached_key(|file| (self.frequency.estimate(&file.info.hash()), file.mtime));
+
+            // Only take the coldest part of the sample each round, so one sample can't wipe out a hot range.
+            let mut evict_count = files.len().div_ceil(4);
+            let mut removed = false;
+            let mut oldest: HashMap<String, FileTime> = HashMap::with_capacity(targets.len());
+            for file in files {
+                if evict_count > 0 && need_free > 0 {
+                    self.remove_cache(&file.info).await;
+
+                    need_free = need_free.saturating_sub(file.size);
+                    evict_count -= 1;
+                    removed = true;
+                    continue;
+                }
+
+                // Keep oldest mtime of the sampled ranges up to date
+                let time = oldest.entry(file.info.static_range()).or_insert(file.mtime);
+                *time = (*time).min(file.mtime);
+            }
+            {
+                let mut cache_date = self.cache_date.lock();
+                for range in targets {
+                    let time = oldest.get(&range).copied().unwrap_or_else(FileTime::now);
+                    cache_date.insert(range, time);
+                }
+            }
+
+            idle = if removed { 0 } else { idle + 1 };
+            if idle > 3 {
+                warn!(
+                    "Cache cleaner can't free enough space: need_free={}bytes, volume={:?}",
+                    need_free, volume
+                );
+                break;
+            }
+        }
+    }
+}
+
+async fn clean_temp_dir(path: &Path) {
+    info!("Deleting old temp files");
+
+    if let Ok(mut dir) = read_dir(path).await {
+        while let Ok(Some(file)) = dir.next_entry().await {
+            let path = file.path();
+            if path.is_file() && file.file_name().to_string_lossy().starts_with("proxyfile_") {
+                debug!("Delete old temp file: {:?}", path);
+                let _ = remove_file(path).await;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Hash, Eq, PartialEq)]
+enum FileType {
+    Jpeg,
+    Png,
+    Gif,
+    Mp4,
+    Webm,
+    Webp,
+    Avif,
+    Jpegxl,
+    Other(String),
+}
+
+impl FileType {
+    fn to_mime(&self) -> Mime {
+        match self {
+            Self::Jpeg => mime::IMAGE_JPEG,
+            Self::Png => mime::IMAGE_PNG,
+            Self::Gif => mime::IMAGE_GIF,
+            Self::Mp4 => "video/mp4".parse().unwrap(),
+            Self::Webm => "video/webm".parse().unwrap(),
+            Self::Webp => "image/webp".parse().unwrap(),
+            Self::Avif => "image/avif".parse().unwrap(),
+            Self::Jpegxl => "image/jxl".parse().unwrap(),
+            Self::Other(_) => mime::APPLICATION_OCTET_STREAM,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::Gif => "gif",
+            Self::Mp4 => "mp4",
+            Self::Webm => "wbm",
+            Self::Webp => "wbp",
+            Self::Avif => "avf",
+            Self::Jpegxl => "jxl",
+            Self::Other(s) => s,
+        }
+    }
+}
+
+impl Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl From<&str> for FileType {
+    fn from(s: &str) -> Self {
+        match s {
+            "jpg" => Self::Jpeg,
+            "png" => Self::Png,
+            "gif" => Self::Gif,
+            "mp4" => Self::Mp4,
+            "wbm" => Self::Webm,
+            "wbp" => Self::Webp,
+            "avf" => Self::Avif,
+            "jxl" => Self::Jpegxl,
+            _ => Self::Other(s.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Hash, Eq, PartialEq)]
+pub struct CacheFileInfo {
+    hash: [u8; 20],
+    size: u32,
+    xres: u32,
+    yres: u32,
+    mime_type: FileType,
+}
+
+impl CacheFileInfo {
+    pub fn from_file_id<T: AsRef<str>>(id: T) -> Option<CacheFileInfo> {
+        let mut part = id.as_ref().split('-');
+        let none_res = part.clone().count() == 3;
+
+        Some(CacheFileInfo {
+            hash: <[u8; 20]>::from_hex(part.next()?).ok()?,
+            size: part.next().and_then(|s| s.parse().ok())?,
+            xres: if none_res { 0 } else { part.next().and_then(|s| s.parse().ok())? },
+            yres: if none_res { 0 } else { part.next().and_then(|s| s.parse().ok())? },
+            mime_type: part.next()?.into(),
+        })
+    }
+
+    /// Get the cache file's size.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn hash(&self) -> [u8; 20] {
+        self.hash
+    }
+
+    pub fn file_id(&self) -> String {
+        let hash = hex::encode(self.hash);
+        if self.xres > 0 {
+            format!("{}-{}-{}-{}-{}", hash, self.size, self.xres, self.yres, self.mime_type)
+        } else {
+            format!("{}-{}-{}", hash, self.size, self.mime_type)
+        }
+    }
+
+    /// Get the static range of the file, the first 4 hex chars of the hash.
+    pub fn static_range(&self) -> String {
+        hex::encode(&self.hash[0..2])
+    }
+
+    fn to_path(&self, cache_dir: &Path) -> PathBuf {
+        let hash = hex::encode(self.hash);
+        let filename = self.file_id();
+        let base = cache_dir.as_os_str();
+        let mut path = PathBuf::with_capacity(base.len() + 7 + filename.len()); // base + 2 level dir + filename length
+        path.push(base);
+        path.push(&hash[0..2]);
+        path.push(&hash[2..4]);
+        path.push(filename);
+        path
+    }
+
+    async fn get_file(&self, cache_dir: &Path) -> Option<PathBuf> {
+        let path = self.to_path(cache_dir);
+        let metadata = metadata(&path).await;
+        if metadata.is_ok() && metadata.unwrap().is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    pub fn mime_type(&self) -> Mime {
+        self.mime_type.to_mime()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Write;
+
+    use tempfile::{tempdir, NamedTempFile};
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::rpc::RPCClient;
+
+    /// Cache manager backed by `MemoryStore`, `dir` holds temp and data files.
+    pub(crate) async fn memory_cache_manager(dir: &Path, static_range: Vec<String>) -> Result<Arc<CacheManager>, Error> {
+        let client = RPCClient::new(1, "testkey", false, 0, Duration::ZERO, 0, Duration::ZERO);
+        CacheManager::new(
+            Arc::new(MemoryStore::default()),
+            dir,
+            dir,
+            client.settings(),
+            &InitSettings::new_for_test(static_range, true),
+            CacheOptions {
+                policy: EvictionPolicy::OldestDir,
+                low_watermark: 95,
+                high_watermark: 98,
+                hot_cache_size: 0,
+                verify_rate: 0,
+            },
+            unbounded_channel().0,
+        )
+        .await
+    }
+
+    /// Info of `data` as a jpg cache file.
+    pub(crate) fn test_file_info(data: &[u8]) -> CacheFileInfo {
+        let mut hasher = openssl::sha::Sha1::new();
+        hasher.update(data);
+        CacheFileInfo::from_file_id(format!("{}-{}-jpg", hex::encode(hasher.finish()), data.len())).unwrap()
+    }
+
+    /// Import `data` as a jpg cache file.
+    pub(crate) async fn import_test_file(manager: &CacheManager, data: &[u8]) -> CacheFileInfo {
+        let info = test_file_info(data);
+        let mut file = NamedTempFile::new_in(&manager.temp_dir).unwrap();
+        file.write_all(data).unwrap();
+        manager.import_cache(&info, &file.into_temp_path()).await;
+        info
+    }
+
+    #[tokio::test]
+    async fn empty_memory_store_starts() {
+        let dir = tempdir().unwrap();
+        let ranges = (0..30).map(|i| format!("{:04x}", i)).collect();
+        assert!(memory_cache_manager(dir.path(), ranges).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn import_get_evict() {
+        let dir = tempdir().unwrap();
+        let manager = memory_cache_manager(dir.path(), vec![]).await.unwrap();
+        let data = b"cache manager test";
+        let info = import_test_file(&manager, data).await;
+        assert_eq!(manager.total_size.load(Relaxed), data.len() as u64);
+
+        match manager.get_file(&info).await {
+            Some(CacheFile::Bytes(bytes)) => assert_eq!(&bytes[..], data),
+            _ => panic!("imported file not found"),
+        }
+
+        manager.free_cache(1, None).await;
+        assert!(manager.get_file(&info).await.is_none());
+        assert_eq!(manager.total_size.load(Relaxed), 0);
+    }
+}
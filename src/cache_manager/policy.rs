@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering::Relaxed};
+
+use clap::ValueEnum;
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 1 << 20; // 4 * 1MiB counters
+/// Total counters of the sketch.
+pub(super) const SKETCH_SIZE: usize = SKETCH_DEPTH * SKETCH_WIDTH;
+const SKETCH_SAMPLE: u64 = SKETCH_WIDTH as u64 * 10; // Halve all counters after this many accesses
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum EvictionPolicy {
+    /// Delete files from the static range with the oldest modification time.
+    OldestDir,
+    /// Delete the least frequently accessed files, sampled across all static ranges.
+    Lfu,
+}
+
+/// Count-min sketch for estimating file access frequency with bounded memory.
+///
+/// Counters are halved periodically, so old accesses fade out.
+pub struct FrequencySketch {
+    counters: Vec<AtomicU8>,
+    additions: AtomicU64,
+}
+
+impl Default for FrequencySketch {
+    fn default() -> Self {
+        Self {
+            counters: (0..SKETCH_SIZE).map(|_| AtomicU8::new(0)).collect(),
+            additions: AtomicU64::new(0),
+        }
+    }
+}
+
+impl FrequencySketch {
+    pub fn increment(&self, hash: &[u8; 20]) {
+        for index in indexes(hash) {
+            let _ = self.counters[index].fetch_update(Relaxed, Relaxed, |c| c.checked_add(1));
+        }
+
+        if self.additions.fetch_add(1, Relaxed) + 1 >= SKETCH_SAMPLE {
+            self.additions.store(0, Relaxed);
+            self.age();
+        }
+    }
+
+    pub fn estimate(&self, hash: &[u8; 20]) -> u8 {
+        indexes(hash)
+            .map(|index| self.counters[index].load(Relaxed))
+            .min()
+            .unwrap_or_default()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.counters.iter().map(|c| c.load(Relaxed)).collect()
+    }
+
+    pub fn restore(&self, data: &[u8]) {
+        if data.len() != self.counters.len() {
+            return;
+        }
+        for (counter, value) in self.counters.iter().zip(data) {
+            counter.store(*value, Relaxed);
+        }
+    }
+
+    fn age(&self) {
+        for counter in &self.counters {
+            let _ = counter.fetch_update(Relaxed, Relaxed, |c| Some(c >> 1));
+        }
+    }
+}
+
+/// SHA-1 is uniform, so each row just uses a different part of the hash.
+fn indexes(hash: &[u8; 20]) -> impl Iterator<Item = usize> + '_ {
+    (0..SKETCH_DEPTH).map(move |row| {
+        let offset = 4 + row * 4; // First 2 bytes is static range, skip it.
+        let value = u32::from_le_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) as usize;
+        row * SKETCH_WIDTH + (value & (SKETCH_WIDTH - 1))
+    })
+}
@@ -0,0 +1,94 @@
+use std::{io::Error, path::PathBuf};
+
+use axum::async_trait;
+use bytes::Bytes;
+use filetime::FileTime;
+use openssl::sha::Sha1;
+use tempfile::TempPath;
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::cache_manager::CacheFileInfo;
+
+/// Storage backend of cache files.
+///
+/// Files are grouped by static range, the first 4 hex chars of the file hash.
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    /// Find a cache file for serving.
+    async fn get(&self, info: &CacheFileInfo) -> Option<CacheFile>;
+
+    /// Move a downloaded file into the store, return the size used by the file.
+    async fn import(&self, info: &CacheFileInfo, file: &TempPath) -> Result<u64, Error>;
+
+    /// Remove a cache file, return the size freed or `None` if the file not exists.
+    async fn remove(&self, info: &CacheFileInfo) -> Result<Option<u64>, Error>;
+
+    /// Update file modification time if it is older than cutoff.
+    async fn touch(&self, info: &CacheFileInfo, cutoff: FileTime);
+
+    /// List static ranges which have a directory in the store.
+    async fn list_ranges(&self) -> Result<Vec<String>, Error>;
+
+    /// Remove a static range and all files in it.
+    async fn remove_range(&self, range: &str) -> Result<(), Error>;
+
+    /// List all valid cache files in a static range.
+    async fn list_files(&self, range: &str) -> Result<Vec<CacheEntry>, Error>;
+
+    /// Last modification time of a static range, any file added or removed should change it.
+    async fn range_mtime(&self, range: &str) -> Option<FileTime>;
+
+    /// Index of the volume which holds the static range.
+    fn range_volume(&self, range: &str) -> Option<usize>;
+
+    /// Status of the underlying storage volumes.
+    fn volumes(&self) -> Vec<VolumeInfo>;
+
+    /// Whether files survive restart, an empty non-persistent store is normal at startup.
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+pub struct VolumeInfo {
+    pub name: String,
+    /// Max size of cache files in the volume, `u64::MAX` means no limit.
+    pub size_limit: u64,
+    pub available_space: Option<u64>,
+    pub healthy: bool,
+}
+
+pub enum CacheFile {
+    Path(PathBuf),
+    Bytes(Bytes),
+}
+
+impl CacheFile {
+    pub async fn sha1(&self) -> Result<[u8; 20], Error> {
+        let mut hasher = Sha1::new();
+        match self {
+            CacheFile::Path(path) => {
+                let mut file = File::open(path).await?;
+                let mut buf = vec![0; 1024 * 1024]; // 1MiB
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&buf[0..n]);
+                }
+            }
+            CacheFile::Bytes(bytes) => hasher.update(bytes),
+        }
+        Ok(hasher.finish())
+    }
+}
+
+pub struct CacheEntry {
+    pub info: CacheFileInfo,
+    /// File content length.
+    pub len: u64,
+    /// Size used on storage.
+    pub size: u64,
+    pub mtime: FileTime,
+}
@@ -0,0 +1,187 @@
+use std::{
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    fs::{read_to_string, write},
+    spawn,
+    time::{sleep, sleep_until, Instant},
+};
+
+use crate::cache_manager::CacheManager;
+
+/// Remember the last fully verified static range.
+pub(super) const VERIFY_PROGRESS_FILE: &str = "verify_progress";
+
+/// Background integrity verifier, re-hash cache files slowly under a IO budget.
+pub(super) struct Verifier {
+    /// Bytes per second, 0 to disable.
+    rate: u64,
+    progress_path: PathBuf,
+    checked_files: AtomicU64,
+    checked_bytes: AtomicU64,
+    corrupt_files: AtomicU64,
+    passes: AtomicU64,
+}
+
+pub struct VerifyStats {
+    pub checked_files: u64,
+    pub checked_bytes: u64,
+    pub corrupt_files: u64,
+    pub passes: u64,
+}
+
+impl Verifier {
+    pub fn new(rate: u64, progress_path: PathBuf) -> Self {
+        Self {
+            rate,
+            progress_path,
+            checked_files: AtomicU64::new(0),
+            checked_bytes: AtomicU64::new(0),
+            corrupt_files: AtomicU64::new(0),
+            passes: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rate > 0
+    }
+
+    pub fn stats(&self) -> VerifyStats {
+        VerifyStats {
+            checked_files: self.checked_files.load(Relaxed),
+            checked_bytes: self.checked_bytes.load(Relaxed),
+            corrupt_files: self.corrupt_files.load(Relaxed),
+            passes: self.passes.load(Relaxed),
+        }
+    }
+
+    async fn load_progress(&self) -> Option<String> {
+        match read_to_string(&self.progress_path).await {
+            Ok(range) => Some(range.trim().to_string()).filter(|r| !r.is_empty()),
+            Err(err) if err.kind() == ErrorKind::NotFound => None,
+            Err(err) => {
+                warn!("Read verify progress error: {}", err);
+                None
+            }
+        }
+    }
+
+    async fn save_progress(&self, range: &str) {
+        if let Err(err) = write(&self.progress_path, range).await {
+            warn!("Save verify progress error: {}", err);
+        }
+    }
+}
+
+impl CacheManager {
+    pub(super) fn start_verifier(new: Arc<Self>) {
+        if !new.verifier.is_enabled() {
+            return;
+        }
+        info!("Start background cache verifier: rate={}bytes/s", new.verifier.rate);
+
+        let manager = Arc::downgrade(&new);
+        spawn(async move {
+            loop {
+                if !CacheManager::verify_pass(&manager).await {
+                    break;
+                }
+                // Rest a while between passes
+                sleep(Duration::from_secs(3600)).await;
+            }
+        });
+    }
+
+    /// Verify all static ranges once, continue from the saved progress. Return false if manager dropped.
+    async fn verify_pass(manager: &Weak<Self>) -> bool {
+        let (mut ranges, start_after) = match manager.upgrade() {
+            Some(manager) => {
+                let ranges = manager.static_range.read().clone();
+                (ranges, manager.verifier.load_progress().await)
+            }
+            None => return false,
+        };
+        ranges.sort_unstable();
+        let start = start_after
+            .and_then(|last| ranges.iter().position(|r| *r > last))
+            .unwrap_or_default();
+
+        for range in &ranges[start..] {
+            let Some(manager) = manager.upgrade() else {
+                return false;
+            };
+            manager.verify_range(range).await;
+            manager.verifier.save_progress(range).await;
+        }
+
+        let Some(manager) = manager.upgrade() else {
+            return false;
+        };
+        manager.verifier.passes.fetch_add(1, Relaxed);
+        manager.verifier.save_progress("").await;
+        manager.log_verify_stats();
+        true
+    }
+
+    async fn verify_range(&self, range: &str) {
+        let files = match self.store.list_files(range).await {
+            Ok(files) => files,
+            // Nothing cached in the range yet
+            Err(err) if err.kind() == ErrorKind::NotFound => return,
+            Err(err) => {
+                error!("Read cache dir {} error: {}", range, err);
+                return;
+            }
+        };
+        if files.is_empty() {
+            return;
+        }
+        debug!("Verifying static range {}: files={}", range, files.len());
+
+        let rate = self.verifier.rate;
+        for entry in files {
+            let next_run = Instant::now() + Duration::from_secs_f64(entry.len as f64 / rate as f64);
+
+            // File may be removed during verification
+            let Some(file) = self.store.get(&entry.info).await else {
+                continue;
+            };
+            match file.sha1().await {
+                Ok(hash) if hash == entry.info.hash() => (),
+                Ok(_) => {
+                    warn!("Corrupt cache file found, removing: {}", entry.info.file_id());
+                    self.remove_cache(&entry.info).await;
+                    self.verifier.corrupt_files.fetch_add(1, Relaxed);
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => {
+                    error!("Verify cache file {} error: {}", entry.info.file_id(), err);
+                    continue;
+                }
+            }
+            self.verifier.checked_files.fetch_add(1, Relaxed);
+            self.verifier.checked_bytes.fetch_add(entry.len, Relaxed);
+
+            sleep_until(next_run).await;
+        }
+    }
+
+    pub(super) fn log_verify_stats(&self) {
+        if !self.verifier.is_enabled() {
+            return;
+        }
+        let stats = self.verifier.stats();
+        info!(
+            "Cache verifier: checked={}files/{}bytes, corrupt={}, passes={}",
+            stats.checked_files, stats.checked_bytes, stats.corrupt_files, stats.passes
+        );
+    }
+}
@@ -20,7 +20,12 @@ use tokio::{
 };
 use unicode_segmentation::UnicodeSegmentation;
 
-use crate::{error::Error, rpc::RPCClient, util};
+use crate::{
+    error::Error,
+    rpc::RPCClient,
+    source_health::{SourceHealth, Transfer},
+    util,
+};
 
 type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -30,14 +35,21 @@ pub struct GalleryDownloader {
     client: Arc<RPCClient>,
     download_dir: PathBuf,
     proxy: Option<Proxy>,
+    source_health: Arc<SourceHealth>,
 }
 
 impl GalleryDownloader {
-    pub fn new<P: AsRef<Path>>(client: Arc<RPCClient>, download_dir: P, proxy: Option<Proxy>) -> GalleryDownloader {
+    pub fn new<P: AsRef<Path>>(
+        client: Arc<RPCClient>,
+        download_dir: P,
+        proxy: Option<Proxy>,
+        source_health: Arc<SourceHealth>,
+    ) -> GalleryDownloader {
         GalleryDownloader {
             client,
             download_dir: download_dir.as_ref().to_path_buf(),
             proxy,
+            source_health,
         }
     }
 
@@ -118,7 +130,11 @@ impl GalleryDownloader {
                         .client
                         .dl_fetch(meta.gid, info.page, info.fileindex, &info.xres, retry)
                         .await
-                        .and_then(|s| Url::parse(&s[0]).ok());
+                        .and_then(|mut sources| {
+                            // Try the healthiest source
+                            self.source_health.sort(&mut sources);
+                            sources.first().and_then(|s| Url::parse(s).ok())
+                        });
                     match url {
                         Some(url) => {
                             start_time = Instant::now();
@@ -126,9 +142,12 @@ impl GalleryDownloader {
                             let meta = meta.clone();
                             let downloaded_files = downloaded_files.clone();
                             let mut reqwest = reqwest.clone();
+                            let source_health = self.source_health.clone();
                             tokio::spawn(async move {
                                 for retry in 0..3 {
-                                    if let Err(err) = download(reqwest.clone(), url.clone(), &path, info.expected_sha1_hash).await {
+                                    let transfer = source_health.transfer(url.as_str());
+                                    if let Err(err) = download(reqwest.clone(), url.clone(), &path, info.expected_sha1_hash, transfer).await
+                                    {
                                         warn!("Gallery file download error: url={}, err={}", url, err);
 
                                         // Try download without proxy at third time
@@ -292,18 +311,35 @@ impl GalleryDownloader {
     }
 }
 
-async fn download<P: AsRef<Path>>(reqwest: reqwest::Client, url: Url, path: P, hash: Option<[u8; 20]>) -> Result<(), BoxError> {
-    let mut file = fs::File::create(&path).await?;
+async fn download<P: AsRef<Path>>(
+    reqwest: reqwest::Client,
+    url: Url,
+    path: P,
+    hash: Option<[u8; 20]>,
+    mut transfer: Transfer<'_>,
+) -> Result<(), BoxError> {
+    let mut file = match fs::File::create(&path).await {
+        Ok(file) => file,
+        Err(err) => {
+            transfer.cancel();
+            return Err(err.into());
+        }
+    };
     let mut stream = reqwest
         .get(url)
         .send()
         .await
         .and_then(|r| r.error_for_status())
         .map(|r| r.bytes_stream())?;
+    transfer.response();
     let mut hasher = Sha1::new();
     while let Some(bytes) = stream.next().await {
         let bytes = &bytes?;
-        file.write_all(bytes).await?;
+        transfer.received(bytes.len() as u64);
+        if let Err(err) = file.write_all(bytes).await {
+            transfer.cancel();
+            return Err(err.into());
+        }
         hasher.update(bytes);
     }
 
@@ -314,6 +350,7 @@ async fn download<P: AsRef<Path>>(reqwest: reqwest::Client, url: Url, path: P, h
         }
     }
 
+    transfer.finish();
     Ok(())
 }
 
@@ -1,7 +1,7 @@
 #![windows_subsystem = "windows"]
 use std::{collections::HashMap, error::Error, ops::RangeInclusive, path::Path, sync::Arc, time::Duration};
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use futures::TryFutureExt;
 use inquire::{
     validator::{ErrorMessage, Validation},
@@ -21,16 +21,22 @@ use tokio::{
         mpsc::{self, Sender, UnboundedReceiver},
         watch,
     },
+    task::spawn_blocking,
     time::{sleep, sleep_until, Instant},
 };
 
 use crate::{
-    cache_manager::{CacheFileInfo, CacheManager},
+    cache_manager::{
+        run_cache_tool, CacheManager, CacheOptions, CacheRoot, CacheStore, CacheToolArgs, EvictionPolicy, FsStore, MemoryStore,
+    },
     gallery_downloader::GalleryDownloader,
     logger::Logger,
+    middleware::{AbuseGuard, AbuseThresholds},
+    route::{FanoutBuffer, UpstreamLimit},
     rpc::RPCClient,
     server::Server,
-    util::{create_dirs, create_http_client},
+    source_health::SourceHealth,
+    util::{create_dirs, create_http_client, parse_size, parse_size_arg},
 };
 
 mod cache_manager;
@@ -41,6 +47,7 @@ mod middleware;
 mod route;
 mod rpc;
 mod server;
+mod source_health;
 mod util;
 
 #[cfg(not(target_env = "msvc"))]
@@ -55,17 +62,47 @@ pub static mut malloc_conf: *const u8 = b"percpu_arena:phycpu,tcache:false,dirty
 const VERSION: &str = concat!(env!("CARGO_PKG_VERSION"), "-", env!("VERGEN_GIT_SHA"));
 pub const CLIENT_VERSION: &str = "1.6.3";
 const MAX_KEY_TIME_DRIFT: RangeInclusive<i64> = -300..=300;
+const PURGE_CHECKPOINT_FILE: &str = "purge_checkpoint";
+const PURGE_WINDOW: u64 = 259200; // 3 days
 
 #[derive(Parser)]
 #[command(version = VERSION)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<SubCommand>,
+
     /// Overrides the port set in the client's settings
     #[arg(long)]
     port: Option<u16>,
 
-    /// Cache data location
-    #[arg(long, default_value_t = String::from("cache"))]
-    cache_dir: String,
+    /// Cache data location, can be specified multiple times to spread static ranges across disks.
+    /// Append `:<SIZE>` to limit the cache size in the dir, e.g. `/mnt/disk1:500G`
+    #[arg(long, default_values_t = [String::from("cache")])]
+    cache_dir: Vec<String>,
+
+    /// Cache storage backend, memory store is lost on restart and should only be used for testing
+    #[arg(long, value_enum, default_value_t = CacheStoreType::Fs)]
+    cache_store: CacheStoreType,
+
+    /// Cache eviction policy used when the cache is full
+    #[arg(long, value_enum, default_value_t = EvictionPolicy::OldestDir)]
+    cache_policy: EvictionPolicy,
+
+    /// Cache usage percent to free space down to when evicting
+    #[arg(long, default_value_t = 95, value_parser = clap::value_parser!(u8).range(1..=100))]
+    cache_low_watermark: u8,
+
+    /// Cache usage percent of size limit or disk space that starts eviction, cache misses are not stored above it
+    #[arg(long, default_value_t = 98, value_parser = clap::value_parser!(u8).range(1..=100))]
+    cache_high_watermark: u8,
+
+    /// Keep the hottest files in memory up to this size, e.g. `512M`. Disabled by default
+    #[arg(long, value_parser = parse_size_arg)]
+    hot_cache_size: Option<u64>,
+
+    /// Re-hash cache files in background under this IO budget per second, e.g. `4M`. Disabled by default
+    #[arg(long, value_parser = parse_size_arg)]
+    verify_rate: Option<u64>,
 
     /// Login data location
     #[arg(long, default_value_t = String::from("data"))]
@@ -95,16 +132,76 @@ struct Args {
     #[arg(long, default_value_t = 0)]
     max_connection: u64,
 
+    /// Seconds to skip file lookup after it failed, 0 to disable
+    #[arg(long, default_value_t = 60)]
+    srfetch_failure_ttl: u64,
+
+    /// Max concurrent file lookups to the RPC server, 0 for unlimited
+    #[arg(long, default_value_t = 16)]
+    srfetch_concurrency: usize,
+
+    /// Seconds a file lookup waits for a concurrency slot before replying 503
+    #[arg(long, default_value_t = 10)]
+    srfetch_queue_timeout: u64,
+
     /// Disable server command ip check
     #[arg(long, default_value_t = false)]
     disable_ip_origin_check: bool,
 
+    /// Override the served bandwidth limit from server settings per second, e.g. `10M`, 0 to disable
+    #[arg(long, value_parser = parse_size_arg)]
+    bandwidth_limit: Option<u64>,
+
+    /// Max concurrent upstream fetches for cache misses, 0 for unlimited
+    #[arg(long, default_value_t = 0)]
+    max_upstream_fetches: usize,
+
+    /// Seconds a cache miss waits for an upstream fetch slot before replying 503
+    #[arg(long, default_value_t = 10)]
+    upstream_queue_timeout: u64,
+
+    /// Inbound bandwidth limit of upstream fetches per second, e.g. `10M`, 0 to disable
+    #[arg(long, value_parser = parse_size_arg, default_value = "0")]
+    upstream_bandwidth_limit: u64,
+
+    /// Temporarily ban IPs sending more file requests per minute than this, 0 to disable
+    #[arg(long, default_value_t = 0)]
+    ban_max_requests: u32,
+
+    /// Temporarily ban IPs failing keystamp check more times per minute than this, 0 to disable
+    #[arg(long, default_value_t = 0)]
+    ban_max_failures: u32,
+
+    /// Download missing files on HEAD requests to warm the cache
+    #[arg(long, default_value_t = false)]
+    head_warm_cache: bool,
+
+    /// Serve runtime stats like the ban list under /stats to local requests
+    #[arg(long, default_value_t = false)]
+    enable_stats: bool,
+
+    /// Use kernel TLS to encrypt responses (Linux only)
+    #[arg(long, default_value_t = false)]
+    enable_ktls: bool,
+
     /// Configure proxy for fetch cache
     #[arg(long)]
     proxy: Option<String>,
 }
 
-type DownloadState = Mutex<HashMap<[u8; 20], (watch::Receiver<Option<Arc<TempPath>>>, Arc<watch::Sender<u64>>)>>;
+#[derive(Subcommand)]
+enum SubCommand {
+    /// Offline cache maintenance, works without login
+    Cache(CacheToolArgs),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CacheStoreType {
+    Fs,
+    Memory,
+}
+
+type DownloadState = Mutex<HashMap<[u8; 20], (watch::Receiver<Option<Arc<TempPath>>>, Arc<watch::Sender<u64>>, Arc<FanoutBuffer>)>>;
 
 pub struct AppState {
     runtime: Handle,
@@ -114,6 +211,12 @@ pub struct AppState {
     cache_manager: Arc<CacheManager>,
     command_channel: Sender<Command>,
     has_proxy: bool,
+    source_health: Arc<SourceHealth>,
+    upstream: Arc<UpstreamLimit>,
+    bandwidth_limit: Option<u64>,
+    abuse_guard: AbuseGuard,
+    head_warm_cache: bool,
+    enable_stats: bool,
 }
 
 pub enum Command {
@@ -144,14 +247,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
     let args = args.unwrap();
 
-    create_dirs(vec![
-        &args.data_dir,
-        &args.cache_dir,
-        &args.log_dir,
-        &args.temp_dir,
-        &args.download_dir,
-    ])
-    .await?;
+    let cache_roots: Vec<CacheRoot> = args.cache_dir.iter().map(|dir| parse_cache_dir(dir)).collect();
+    if let Some(SubCommand::Cache(command)) = args.command {
+        return Ok(run_cache_tool(command, cache_roots, Path::new(&args.data_dir), Path::new(&args.temp_dir)).await?);
+    }
+
+    let mut dirs = vec![args.data_dir.as_str(), &args.log_dir, &args.temp_dir, &args.download_dir];
+    dirs.extend(cache_roots.iter().filter_map(|root| root.path().to_str()));
+    create_dirs(dirs).await?;
 
     // Init logger
     let mut logger = Logger::init(args.log_dir).unwrap();
@@ -163,18 +266,45 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Some(i) => i,
         None => setup(&args.data_dir).await?,
     };
-    let client = Arc::new(RPCClient::new(id, &key, args.disable_ip_origin_check, args.max_connection));
+    let client = Arc::new(RPCClient::new(
+        id,
+        &key,
+        args.disable_ip_origin_check,
+        args.max_connection,
+        Duration::from_secs(args.srfetch_failure_ttl),
+        args.srfetch_concurrency,
+        Duration::from_secs(args.srfetch_queue_timeout),
+    ));
     let init_settings = client.login().await?;
 
     let (shutdown_send, shutdown_recv) = mpsc::unbounded_channel::<()>();
     let settings = client.settings();
     logger.config().write_info(!settings.disable_logging());
-    delete_java_cache_data(args.data_dir).await; // Rust cache data incompatible with Java, so we must delete it
+    delete_java_cache_data(&args.data_dir).await; // Rust cache data incompatible with Java, so we must delete it
+    let store: Arc<dyn CacheStore> = match args.cache_store {
+        CacheStoreType::Fs => {
+            let store = spawn_blocking(move || {
+                let store = FsStore::new(cache_roots);
+                store.recover();
+                store
+            });
+            Arc::new(store.await.unwrap())
+        }
+        CacheStoreType::Memory => Arc::new(MemoryStore::default()),
+    };
     let cache_manager = CacheManager::new(
-        args.cache_dir,
-        args.temp_dir,
+        store,
+        &args.temp_dir,
+        &args.data_dir,
         settings.clone(),
         &init_settings,
+        CacheOptions {
+            policy: args.cache_policy,
+            low_watermark: args.cache_low_watermark,
+            high_watermark: args.cache_high_watermark,
+            hot_cache_size: args.hot_cache_size.unwrap_or(0),
+            verify_rate: args.verify_rate.unwrap_or(0),
+        },
         shutdown_send.clone(),
     )
     .await?;
@@ -192,6 +322,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         None => None,
     };
     let has_proxy = proxy.is_some();
+    let source_health = Arc::new(SourceHealth::default());
+    let upstream = Arc::new(UpstreamLimit::new(
+        args.max_upstream_fetches,
+        Duration::from_secs(args.upstream_queue_timeout),
+        args.upstream_bandwidth_limit,
+    ));
     // Command channel
     let (tx, mut rx) = mpsc::channel::<Command>(1);
 
@@ -207,7 +343,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
             cache_manager: cache_manager.clone(),
             command_channel: tx.clone(),
             has_proxy,
+            source_health: source_health.clone(),
+            upstream: upstream.clone(),
+            bandwidth_limit: args.bandwidth_limit,
+            abuse_guard: AbuseGuard::new(AbuseThresholds {
+                max_requests: args.ban_max_requests,
+                max_failures: args.ban_max_failures,
+            }),
+            head_warm_cache: args.head_warm_cache,
+            enable_stats: args.enable_stats,
         },
+        args.enable_ktls,
     );
     let server_handle = server.handle();
 
@@ -221,11 +367,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
     client.refresh_settings().await;
 
     // Check purge list
-    if let Some(list) = client.get_purgelist(259200).await {
-        for info in list.iter().filter_map(CacheFileInfo::from_file_id) {
-            cache_manager.remove_cache(&info).await;
-        }
-    }
+    let data_dir = Path::new(&args.data_dir).to_path_buf();
+    check_purge_list(&client, &cache_manager, &data_dir).await;
 
     info!("H@H initialization completed successfully. Starting normal operation");
 
@@ -234,6 +377,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let cache_manager2 = cache_manager.clone();
     let downloader = Arc::new(Mutex::new(None));
     let downloader2 = downloader.clone();
+    let source_health2 = source_health.clone();
     let logger_config = logger.config();
     tokio::spawn(async move {
         let mut last_overload = Instant::now().checked_sub(Duration::from_secs(30)).unwrap_or_else(Instant::now);
@@ -263,7 +407,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 Command::StartDownloader => {
                     let mut downloader = downloader2.lock();
                     if downloader.is_none() {
-                        let new = GalleryDownloader::new(client2.clone(), &args.download_dir, proxy.clone());
+                        let new = GalleryDownloader::new(client2.clone(), &args.download_dir, proxy.clone(), source_health2.clone());
                         let downloader3 = downloader2.clone();
                         *downloader = Some(tokio::spawn(async move {
                             new.run().await;
@@ -284,6 +428,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Schedule task
     let client3 = client.clone();
+    let cache_manager3 = cache_manager.clone();
     let keepalive = tokio::spawn(async move {
         let mut counter: u32 = 0;
         let mut next_run = Instant::now() + Duration::from_secs(10);
@@ -299,13 +444,22 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 let _ = shutdown_send.send(()); // Check fail, shutdown.
             }
 
+            // Report suppressed lookup, upstream queue timeout and source health every 10min
+            if counter % 60 == 59 {
+                let suppressed = client3.srfetch_suppressed();
+                if suppressed > 0 {
+                    info!("Suppressed srfetch lookups: {}", suppressed);
+                }
+                let rejected = upstream.rejected();
+                if rejected > 0 {
+                    info!("Cache misses rejected by upstream queue timeout: {}", rejected);
+                }
+                source_health.log_stats();
+            }
+
             // Check purge list every 7hr
             if counter % 2160 == 2159 {
-                if let Some(list) = client3.get_purgelist(43200).await {
-                    for info in list.iter().filter_map(CacheFileInfo::from_file_id) {
-                        cache_manager.remove_cache(&info).await;
-                    }
-                }
+                check_purge_list(&client3, &cache_manager, &data_dir).await;
             }
 
             counter = counter.wrapping_add(1);
@@ -325,6 +479,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     info!("Shutdown in progress - please wait");
     sleep(Duration::from_secs(15)).await;
     server.shutdown().await;
+    cache_manager3.save_index().await;
     logger.shutdown().await;
     Ok(())
 }
@@ -397,6 +552,85 @@ After registering, enter your ID and Key below to start your client.
     Ok((id, key))
 }
 
+/// Fetch the purge list since the last successful check and remove purged files.
+///
+/// The check time is saved in data dir, a gap longer than `PURGE_WINDOW` cross-checks the whole cache in background,
+/// the checkpoint only advances after the check succeeded. A missing checkpoint is initialized with the default window.
+async fn check_purge_list(client: &RPCClient, cache_manager: &Arc<CacheManager>, data_dir: &Path) {
+    let path = data_dir.join(PURGE_CHECKPOINT_FILE);
+    let last = fs::read_to_string(&path).await.ok().and_then(|s| s.trim().parse::<i64>().ok());
+    let now = client.get_timestemp();
+
+    // Overlap a bit to cover clock adjustment
+    let delta = last.map_or(PURGE_WINDOW, |last| (now - last).max(0) as u64 + 60);
+    let full_check = delta > PURGE_WINDOW;
+    if full_check {
+        if !cache_manager.start_purge_check() {
+            return; // Previous cross-check still running
+        }
+        info!("Purge list checkpoint is outdated, cross-check whole cache: delta={}s", delta);
+    } else if last.is_none() {
+        info!("Purge list checkpoint is missing, initialize it");
+    }
+
+    let Some(list) = client.get_purgelist(delta).await else {
+        warn!("Fetch purge list fail");
+        if full_check {
+            cache_manager.finish_purge_check();
+        }
+        return;
+    };
+
+    if !full_check {
+        cache_manager.purge(&list, false).await;
+        save_purge_checkpoint(&path, now).await;
+        return;
+    }
+
+    // Listing the whole cache takes a while, don't block startup or keepalive
+    let cache_manager = cache_manager.clone();
+    tokio::spawn(async move {
+        if cache_manager.purge(&list, true).await {
+            save_purge_checkpoint(&path, now).await;
+        }
+        cache_manager.finish_purge_check();
+    });
+}
+
+/// Write checkpoint to a temp file then rename, so a crash never leaves a truncated checkpoint.
+async fn save_purge_checkpoint(path: &Path, time: i64) {
+    let temp = path.with_extension("tmp");
+    let result = async {
+        let mut file = File::create(&temp).await?;
+        file.write_all(time.to_string().as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&temp, path).await
+    };
+    if let Err(err) = result.await {
+        error!("Save purge list checkpoint error: {}", err);
+    }
+}
+
+/// Parse `<PATH>[:<SIZE>]` cache dir option.
+fn parse_cache_dir(dir: &str) -> CacheRoot {
+    let (path, size) = split_cache_dir(dir);
+    CacheRoot::new(path, size)
+}
+
+/// Split the size suffix off a cache dir option, 0 if none.
+///
+/// Only a suffix which parses as a size is split, and never from a bare drive letter,
+/// so Windows paths like `C:\cache` are kept whole.
+fn split_cache_dir(dir: &str) -> (&str, u64) {
+    if let Some((path, size)) = dir.rsplit_once(':') {
+        let drive = path.len() == 1 && path.starts_with(|c: char| c.is_ascii_alphabetic());
+        if let Some(size) = parse_size(size).filter(|_| !path.is_empty() && !drive) {
+            return (path, size);
+        }
+    }
+    (dir, 0)
+}
+
 async fn delete_java_cache_data<P: AsRef<Path>>(data_dir: P) {
     let base = data_dir.as_ref();
     let _ = remove_file(base.join("pcache_info")).await;
@@ -510,3 +744,17 @@ fn switch_window(hide: bool) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_dir_size_suffix() {
+        assert_eq!(split_cache_dir("/mnt/cache"), ("/mnt/cache", 0));
+        assert_eq!(split_cache_dir("/mnt/cache:10G"), ("/mnt/cache", 10 << 30));
+        assert_eq!(split_cache_dir(r"C:\cache"), (r"C:\cache", 0));
+        assert_eq!(split_cache_dir(r"C:\cache:512M"), (r"C:\cache", 512 << 20));
+        assert_eq!(split_cache_dir("D:100"), ("D:100", 0));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Weak},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use log::{info, warn};
+use parking_lot::Mutex;
+use tokio::{
+    spawn,
+    time::{sleep, Instant},
+};
+use tower::{Layer, Service};
+
+use crate::server::ClientAddr;
+
+const WINDOW: Duration = Duration::from_secs(60);
+const BASE_BAN: Duration = Duration::from_secs(60);
+const MAX_BAN: Duration = Duration::from_secs(86400);
+/// Forget strikes of an IP which behaved for this long after its last ban.
+const STRIKE_RESET: Duration = Duration::from_secs(86400);
+/// Max tracked IPs, new IPs are not tracked when full of active records.
+const MAX_RECORDS: usize = 65536;
+
+/// Per IP limits of `/h` requests in a minute, 0 to disable.
+#[derive(Clone, Copy, Default)]
+pub struct AbuseThresholds {
+    pub max_requests: u32,
+    pub max_failures: u32,
+}
+
+/// Response extension set by the file handler when the keystamp check failed.
+#[derive(Clone, Copy)]
+pub struct KeystampRejected;
+
+/// Temporary ban IPs which send too many file requests or bad keystamps, ban time doubles on every repeat.
+#[derive(Clone)]
+pub struct AbuseGuard {
+    data: Arc<AbuseGuardState>,
+}
+
+struct AbuseGuardState {
+    thresholds: AbuseThresholds,
+    records: Mutex<HashMap<IpAddr, IpRecord>>,
+}
+
+struct IpRecord {
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    /// Number of bans, decide the next ban time.
+    strikes: u32,
+    banned_until: Option<Instant>,
+    /// Requests rejected by the current ban.
+    ban_hits: u64,
+    last_seen: Instant,
+}
+
+/// Snapshot of a banned IP.
+pub struct BanEntry {
+    pub ip: IpAddr,
+    pub strikes: u32,
+    pub remaining: Duration,
+    pub hits: u64,
+}
+
+impl AbuseGuard {
+    pub fn new(thresholds: AbuseThresholds) -> Self {
+        let new = Self {
+            data: Arc::new(AbuseGuardState {
+                thresholds,
+                records: Mutex::new(HashMap::new()),
+            }),
+        };
+        if thresholds.max_requests != 0 || thresholds.max_failures != 0 {
+            AbuseGuardState::start_background_task(Arc::downgrade(&new.data));
+        }
+        new
+    }
+
+    /// Currently banned IPs, most rejected requests first.
+    pub fn ban_list(&self) -> Vec<BanEntry> {
+        self.data.ban_list()
+    }
+}
+
+impl AbuseGuardState {
+    fn is_enabled(&self) -> bool {
+        self.thresholds.max_requests != 0 || self.thresholds.max_failures != 0
+    }
+
+    /// Log the ban list and drop idle records every 10min.
+    fn start_background_task(state: Weak<Self>) {
+        spawn(async move {
+            loop {
+                sleep(Duration::from_secs(600)).await;
+                let Some(state) = state.upgrade() else {
+                    break;
+                };
+                state.prune();
+                for ban in state.ban_list() {
+                    info!(
+                        "Banned IP: ip={}, strikes={}, remaining={}s, hits={}",
+                        ban.ip,
+                        ban.strikes,
+                        ban.remaining.as_secs(),
+                        ban.hits
+                    );
+                }
+            }
+        });
+    }
+
+    /// Currently banned IPs.
+    fn ban_list(&self) -> Vec<BanEntry> {
+        let now = Instant::now();
+        let mut list: Vec<BanEntry> = self
+            .records
+            .lock()
+            .iter()
+            .filter_map(|(ip, record)| {
+                let until = record.banned_until.filter(|until| *until > now)?;
+                Some(BanEntry {
+                    ip: *ip,
+                    strikes: record.strikes,
+                    remaining: until - now,
+                    hits: record.ban_hits,
+                })
+            })
+            .collect();
+        list.sort_unstable_by(|a, b| b.hits.cmp(&a.hits));
+        list
+    }
+
+    fn prune(&self) {
+        Self::prune_records(&mut self.records.lock(), Instant::now());
+    }
+
+    fn prune_records(records: &mut HashMap<IpAddr, IpRecord>, now: Instant) {
+        records.retain(|_, record| {
+            let banned = record.banned_until.is_some_and(|until| until > now);
+            banned || now - record.last_seen < if record.strikes > 0 { STRIKE_RESET } else { WINDOW }
+        });
+    }
+
+    /// Count a request, return the remaining ban time if the IP is banned.
+    fn check(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut records = self.records.lock();
+        if records.len() >= MAX_RECORDS && !records.contains_key(&ip) {
+            Self::prune_records(&mut records, now);
+            if records.len() >= MAX_RECORDS {
+                return None;
+            }
+        }
+        let record = records.entry(ip).or_insert_with(|| IpRecord {
+            window_start: now,
+            requests: 0,
+            failures: 0,
+            strikes: 0,
+            banned_until: None,
+            ban_hits: 0,
+            last_seen: now,
+        });
+        record.last_seen = now;
+
+        if let Some(until) = record.banned_until.filter(|until| *until > now) {
+            record.ban_hits += 1;
+            return Some(until - now);
+        }
+        if now - record.window_start >= WINDOW {
+            record.window_start = now;
+            record.requests = 0;
+            record.failures = 0;
+        }
+
+        record.requests += 1;
+        let max = self.thresholds.max_requests;
+        if max != 0 && record.requests > max {
+            return Some(Self::ban(ip, record, now, "request rate"));
+        }
+        None
+    }
+
+    /// Count a keystamp failure, ban the IP if it failed too often.
+    fn record_failure(&self, ip: IpAddr) {
+        let max = self.thresholds.max_failures;
+        if max == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(record) = self.records.lock().get_mut(&ip) {
+            record.failures += 1;
+            if record.failures > max && record.banned_until.is_none_or(|until| until <= now) {
+                Self::ban(ip, record, now, "keystamp failures");
+            }
+        }
+    }
+
+    fn ban(ip: IpAddr, record: &mut IpRecord, now: Instant, reason: &str) -> Duration {
+        // Start over if the IP behaved for a long time since last ban
+        if record.banned_until.is_some_and(|until| now - until > STRIKE_RESET) {
+            record.strikes = 0;
+        }
+        let duration = BASE_BAN.saturating_mul(1 << record.strikes.min(16)).min(MAX_BAN);
+        record.strikes += 1;
+        record.banned_until = Some(now + duration);
+        record.ban_hits = 0;
+        record.requests = 0;
+        record.failures = 0;
+        warn!(
+            "Ban IP for {}: ip={}, duration={}s, strikes={}",
+            reason,
+            ip,
+            duration.as_secs(),
+            record.strikes
+        );
+        duration
+    }
+}
+
+impl<S> Layer<S> for AbuseGuard {
+    type Service = AbuseGuardMiddleware<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        AbuseGuardMiddleware {
+            data: self.data.clone(),
+            service,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AbuseGuardMiddleware<S> {
+    data: Arc<AbuseGuardState>,
+    service: S,
+}
+
+impl<S> Service<Request> for AbuseGuardMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Response = Response;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let ip = req.extensions().get::<ClientAddr>().map(|addr| addr.ip());
+        let ip = ip.filter(|_| self.data.is_enabled() && req.uri().path().starts_with("/h/"));
+        let Some(ip) = ip else {
+            return Box::pin(self.service.call(req));
+        };
+
+        if let Some(remaining) = self.data.check(ip) {
+            let retry_after = remaining.as_secs().max(1).to_string();
+            return Box::pin(async move { Ok((StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry_after)]).into_response()) });
+        }
+
+        let data = self.data.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let res = fut.await?;
+            if res.extensions().get::<KeystampRejected>().is_some() {
+                data.record_failure(ip);
+            }
+            Ok(res)
+        })
+    }
+}
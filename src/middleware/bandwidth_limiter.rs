@@ -0,0 +1,225 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, HttpBody},
+    extract::Request,
+    response::Response,
+};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use http_body::{Frame, SizeHint};
+use pin_project_lite::pin_project;
+use tokio::time::{sleep, Sleep};
+use tower::{Layer, Service};
+
+use crate::{rpc::Settings, util::TokenBucket};
+
+/// Global token bucket limiting the bandwidth of file and speed test responses.
+#[derive(Clone)]
+pub(super) struct BandwidthLimiter {
+    data: Arc<BandwidthLimiterState>,
+}
+
+struct BandwidthLimiterState {
+    settings: Arc<Settings>,
+    /// Local limit in bytes/sec, replace `throttle_bytes` from server settings.
+    rate_override: Option<u64>,
+    bucket: TokenBucket,
+}
+
+impl BandwidthLimiter {
+    pub fn new(settings: Arc<Settings>, rate_override: Option<u64>) -> Self {
+        Self {
+            data: Arc::new(BandwidthLimiterState {
+                settings,
+                rate_override,
+                bucket: TokenBucket::default(),
+            }),
+        }
+    }
+}
+
+impl BandwidthLimiterState {
+    /// Bytes per second, 0 means unlimited.
+    fn rate(&self) -> u64 {
+        self.rate_override.unwrap_or_else(|| self.settings.throttle_bytes())
+    }
+
+    /// Take tokens for sent data, return how long to wait until the debt is paid.
+    fn take(&self, size: usize) -> Duration {
+        self.bucket.take(self.rate(), size)
+    }
+}
+
+impl<S> Layer<S> for BandwidthLimiter {
+    type Service = BandwidthLimiterMiddleware<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        BandwidthLimiterMiddleware {
+            data: self.data.clone(),
+            service,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(super) struct BandwidthLimiterMiddleware<S> {
+    data: Arc<BandwidthLimiterState>,
+    service: S,
+}
+
+impl<S> Service<Request> for BandwidthLimiterMiddleware<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+    type Response = Response<BandwidthLimitedBody>;
+
+    fn poll_ready(&mut self, ctx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(ctx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let path = req.uri().path();
+        let limited = (path.starts_with("/h/") || path.starts_with("/t/")) && self.data.rate() > 0;
+        let limiter = limited.then(|| self.data.clone());
+
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            Ok(res.map(|body| BandwidthLimitedBody {
+                body,
+                limiter,
+                buffer: Bytes::new(),
+                delayed: None,
+            }))
+        })
+    }
+}
+
+pin_project! {
+    pub(super) struct BandwidthLimitedBody {
+        #[pin]
+        body: Body,
+        limiter: Option<Arc<BandwidthLimiterState>>,
+        // Data of the current frame not released yet
+        buffer: Bytes,
+        // Chunk waiting for tokens, released after the delay
+        delayed: Option<(Bytes, Pin<Box<Sleep>>)>,
+    }
+}
+
+impl BandwidthLimitedBody {
+    fn buffered(&self) -> u64 {
+        (self.buffer.len() + self.delayed.as_ref().map_or(0, |(chunk, _)| chunk.len())) as u64
+    }
+}
+
+impl HttpBody for BandwidthLimitedBody {
+    type Data = Bytes;
+    type Error = <Body as HttpBody>::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let Some(limiter) = this.limiter.as_ref() else {
+            return this.body.poll_frame(cx);
+        };
+
+        if this.delayed.is_none() {
+            if this.buffer.is_empty() {
+                match ready!(this.body.poll_frame(cx)) {
+                    Some(Ok(frame)) => match frame.into_data() {
+                        Ok(data) => *this.buffer = data,
+                        Err(frame) => return Poll::Ready(Some(Ok(frame))), // Trailers
+                    },
+                    other => return Poll::Ready(other),
+                }
+            }
+
+            // Split large frame, so a chunk never takes more than the 1s burst of the bucket
+            let rate = limiter.rate();
+            let size = if rate == 0 {
+                this.buffer.len()
+            } else {
+                this.buffer.len().min(rate as usize)
+            };
+            let chunk = this.buffer.split_to(size);
+            let wait = limiter.take(chunk.len());
+            if wait.is_zero() {
+                return Poll::Ready(Some(Ok(Frame::data(chunk))));
+            }
+            *this.delayed = Some((chunk, Box::pin(sleep(wait))));
+        }
+
+        // Wait for tokens before releasing the chunk
+        let (_, delay) = this.delayed.as_mut().unwrap();
+        ready!(delay.as_mut().poll(cx));
+        let (chunk, _) = this.delayed.take().unwrap();
+        Poll::Ready(Some(Ok(Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buffered() == 0 && self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered = self.buffered();
+        let hint = self.body.size_hint();
+        let mut new = SizeHint::new();
+        new.set_lower(hint.lower() + buffered);
+        if let Some(upper) = hint.upper() {
+            new.set_upper(upper + buffered);
+        }
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::pin;
+
+    use futures::poll;
+    use http_body_util::BodyExt;
+    use tokio::time;
+
+    use super::*;
+    use crate::rpc::RPCClient;
+
+    #[tokio::test]
+    async fn split_and_wait_before_release() {
+        time::pause();
+        let client = RPCClient::new(1, "testkey", false, 0, Duration::ZERO, 0, Duration::ZERO);
+        let limiter = BandwidthLimiter::new(client.settings(), Some(64 * 1024));
+        let mut body = BandwidthLimitedBody {
+            body: Body::from(vec![0u8; 96 * 1024]),
+            limiter: Some(limiter.data),
+            buffer: Bytes::new(),
+            delayed: None,
+        };
+
+        // First second is the burst
+        let first = body.frame().await.unwrap().unwrap().into_data().unwrap();
+        assert_eq!(first.len(), 64 * 1024);
+
+        let mut second = pin!(body.frame());
+        assert!(poll!(&mut second).is_pending());
+        // 32KiB at 64KiB/s, timer has millisecond resolution
+        time::advance(Duration::from_millis(490)).await;
+        assert!(poll!(&mut second).is_pending());
+        time::advance(Duration::from_millis(20)).await;
+        let Poll::Ready(Some(Ok(second))) = poll!(&mut second) else {
+            panic!("frame not released after wait");
+        };
+        assert_eq!(second.into_data().unwrap().len(), 32 * 1024);
+        assert!(body.frame().await.is_none());
+    }
+}
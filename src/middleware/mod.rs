@@ -1,3 +1,5 @@
+mod abuse_guard;
+mod bandwidth_limiter;
 mod connection_counter;
 mod logger;
 
@@ -16,8 +18,9 @@ use axum::{
 use const_format::concatcp;
 use tower::{timeout::TimeoutLayer, ServiceBuilder};
 
+pub use crate::middleware::abuse_guard::{AbuseGuard, AbuseThresholds, KeystampRejected};
 use crate::{
-    middleware::{connection_counter::ConnectionCounter, logger::Logger},
+    middleware::{bandwidth_limiter::BandwidthLimiter, connection_counter::ConnectionCounter, logger::Logger},
     AppState, CLIENT_VERSION,
 };
 
@@ -30,6 +33,8 @@ pub fn register_layer(router: Router<Arc<AppState>>, data: &AppState) -> Router<
                 .layer(HandleErrorLayer::new(|_| async { StatusCode::SERVICE_UNAVAILABLE }))
                 .layer(TimeoutLayer::new(Duration::from_secs(181))),
         )
+        .layer(BandwidthLimiter::new(data.rpc.settings(), data.bandwidth_limit))
+        .layer(data.abuse_guard.clone())
         .layer(Logger::default())
         .layer(ConnectionCounter::new(data.rpc.settings(), data.command_channel.clone()))
         .layer(middleware::map_response(default_headers))
@@ -5,18 +5,16 @@ use axum::{
     body::Body,
     extract::{Path, Request, State},
     http::{
-        header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
-        HeaderName, HeaderValue,
+        header::{ACCEPT_RANGES, CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, RANGE},
+        HeaderName, HeaderValue, Method, StatusCode,
     },
     response::{IntoResponse, Response},
 };
 use bytes::BytesMut;
-use futures::StreamExt;
-use log::error;
-use openssl::sha::Sha1;
+use log::{debug, error};
 use tokio::{
-    fs::{File, OpenOptions},
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
     sync::watch,
     time::timeout,
 };
@@ -24,9 +22,15 @@ use tower::util::ServiceExt;
 use tower_http::services::ServeFile;
 
 use crate::{
-    cache_manager::CacheFileInfo,
-    route::{forbidden, not_found, parse_additional},
-    util::{create_http_client, string_to_hash},
+    cache_manager::{CacheFile, CacheFileInfo},
+    middleware::KeystampRejected,
+    route::{
+        check_precondition,
+        download::{download, failover, report_bad_sources},
+        forbidden, not_found, parse_additional, parse_range, service_unavailable, ByteRange, FanoutBuffer, Precondition,
+    },
+    rpc::SrFetchError,
+    util::string_to_hash,
     AppState,
 };
 
@@ -37,7 +41,7 @@ const CACHE_HEADER: (HeaderName, HeaderValue) = (CACHE_CONTROL, HeaderValue::fro
 pub(super) async fn hath(
     Path((file_id, additional, file_name)): Path<(String, String, String)>,
     data: State<Arc<AppState>>,
-    req: Request,
+    mut req: Request,
 ) -> impl IntoResponse {
     let additional = parse_additional(&additional);
     let mut keystamp = additional.get("keystamp").unwrap_or(&"").split('-');
@@ -54,7 +58,9 @@ pub(super) async fn hath(
     let time_diff = &(data.rpc.get_timestemp() - time.parse::<i64>().unwrap_or_default());
     let hash_string = format!("{}-{}-{}-hotlinkthis", time, file_id, data.rpc.key());
     if time.is_empty() || hash.is_empty() || !TTL.contains(time_diff) || !string_to_hash(hash_string).starts_with(hash) {
-        return forbidden();
+        let mut res = forbidden();
+        res.extensions_mut().insert(KeystampRejected);
+        return res;
     };
 
     // Check cache hit
@@ -62,142 +68,200 @@ pub(super) async fn hath(
         Some(info) => info,
         None => return not_found(),
     };
-    if let Some(path) = data.cache_manager.get_file(&info).await {
-        let mut res = ServeFile::new_with_mime(path, &info.mime_type()).oneshot(req).await.unwrap();
-        let header = res.headers_mut();
-        header.insert(CACHE_HEADER.0, CACHE_HEADER.1);
-        header.insert(content_disposition.0, content_disposition.1);
-        return res.map(Body::new);
+
+    // Conditional request, content is identified by its hash
+    let etag = format!("\"{}\"", hex::encode(info.hash()));
+    match check_precondition(req.headers_mut(), &etag) {
+        Precondition::Pass => (),
+        Precondition::NotModified => {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(ETAG, &etag)
+                .header(CACHE_HEADER.0, CACHE_HEADER.1)
+                .body(Body::empty())
+                .unwrap();
+        }
+        Precondition::Failed => {
+            return Response::builder()
+                .status(StatusCode::PRECONDITION_FAILED)
+                .header(CONTENT_LENGTH, 0)
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
+
+    match data.cache_manager.get_file(&info).await {
+        Some(CacheFile::Path(path)) => {
+            let mut res = ServeFile::new_with_mime(path, &info.mime_type()).oneshot(req).await.unwrap();
+            let header = res.headers_mut();
+            header.insert(CACHE_HEADER.0, CACHE_HEADER.1);
+            header.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+            header.insert(content_disposition.0, content_disposition.1);
+            return res.map(Body::new);
+        }
+        Some(CacheFile::Bytes(bytes)) => {
+            let len = bytes.len() as u64;
+            let builder = Response::builder()
+                .header(ACCEPT_RANGES, "bytes")
+                .header(ETAG, &etag)
+                .header(CONTENT_TYPE, HeaderValue::from_maybe_shared(info.mime_type().to_string()).unwrap())
+                .header(CACHE_HEADER.0, CACHE_HEADER.1)
+                .header(content_disposition.0, content_disposition.1);
+            return match parse_range(req.headers().get(RANGE), len) {
+                ByteRange::Full => builder.header(CONTENT_LENGTH, len).body(Body::from(bytes)),
+                ByteRange::Partial(range) => builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, len))
+                    .header(CONTENT_LENGTH, range.end - range.start)
+                    .body(Body::from(bytes.slice(range.start as usize..range.end as usize))),
+                ByteRange::Unsatisfiable => builder
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", len))
+                    .header(CONTENT_LENGTH, 0)
+                    .body(Body::empty()),
+            }
+            .unwrap();
+        }
+        None => (),
     }
 
     // Cache miss, proxy request
     let file_size = info.size() as u64;
+    let range = match parse_range(req.headers().get(RANGE), file_size) {
+        ByteRange::Full => None,
+        ByteRange::Partial(range) => Some(range),
+        ByteRange::Unsatisfiable => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", file_size))
+                .header(CONTENT_LENGTH, 0)
+                .body(Body::empty())
+                .unwrap();
+        }
+    };
+
+    let mut builder = Response::builder()
+        .header(ACCEPT_RANGES, "bytes")
+        .header(ETAG, &etag)
+        .header(CONTENT_TYPE, HeaderValue::from_maybe_shared(info.mime_type().to_string()).unwrap())
+        .header(CACHE_HEADER.0, CACHE_HEADER.1)
+        .header(content_disposition.0, content_disposition.1);
+    let (start, end) = match range {
+        Some(range) => {
+            builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, file_size));
+            (range.start, range.end)
+        }
+        None => (0, file_size),
+    };
+
+    // Answer HEAD from metadata, don't start download unless configured
+    if req.method() == Method::HEAD && !data.head_warm_cache {
+        return builder.header(CONTENT_LENGTH, end - start).body(Body::empty()).unwrap();
+    }
 
     // Check if the file is already downloading
     let (temp_tx, temp_rx) = watch::channel(None); // Tempfile
     let tx = Arc::new(watch::channel(0).0); // Download progress
+    let fanout = Arc::new(FanoutBuffer::default()); // Downloaded data
     let state;
     {
         let mut download_state = data.download_state.lock();
         state = download_state.get(&info.hash()).cloned();
         // Tracking download progress
         if state.is_none() {
-            download_state.insert(info.hash(), (temp_rx.clone(), tx.clone()));
+            download_state.insert(info.hash(), (temp_rx.clone(), tx.clone(), fanout.clone()));
         }
     }
 
-    let (temp_path, mut rx) = if let Some((mut tempfile, progress)) = state {
+    let (temp_path, mut rx, fanout) = if let Some((mut tempfile, progress, fanout)) = state {
         let tempfile = tempfile.wait_for(Option::is_some).await;
         if let Err(err) = tempfile {
+            // Download cancelled or timed out in the fetch queue
             error!("Waiting tempfile create error: {}", err);
-            data.download_state.lock().remove(&info.hash());
-            return not_found();
+            remove_download_state(&data, &info.hash(), &progress);
+            return service_unavailable();
         }
-        (tempfile.unwrap().as_ref().unwrap().clone(), progress.subscribe())
+        (tempfile.unwrap().as_ref().unwrap().clone(), progress.subscribe(), fanout)
     } else {
         // Make sure the state will be removed when cancellation.
         let data2 = data.clone();
-        let state_guard = scopeguard::guard(info.hash(), move |hash| {
-            data2.download_state.lock().remove(&hash);
+        let state_guard = scopeguard::guard((info.hash(), tx.clone()), move |(hash, progress)| {
+            remove_download_state(&data2, &hash, &progress);
         });
 
+        // Wait for a fetch slot, ask the client to retry later if the queue is too long
+        let Ok(permit) = data.upstream.acquire().await else {
+            debug!("Upstream fetch queue timeout: file={}", file_id);
+            return service_unavailable();
+        };
+
         let temp_path = Arc::new(data.cache_manager.create_temp_file().await);
         temp_tx.send_replace(Some(temp_path.clone()));
 
-        let sources = match data.rpc.sr_fetch(file_index, xres, &file_id).await {
-            Some(v) => v,
-            None => return not_found(),
+        let mut sources = match data.rpc.sr_fetch(file_index, xres, &file_id).await {
+            Ok(v) => v,
+            Err(SrFetchError::Busy) => return service_unavailable(),
+            Err(SrFetchError::NotFound) => return not_found(),
         };
+        data.source_health.sort(&mut sources);
+
+        // Disk pressure admission, file is only streamed to client when not stored
+        let store = data.cache_manager.should_store(&info);
 
         // Download worker
         let tx2: Arc<watch::Sender<u64>> = tx.clone();
         let info2 = info.clone();
         let temp_path2 = temp_path.clone();
+        let fanout2 = fanout.clone();
+        let (file_index, xres) = (file_index.to_string(), xres.to_string());
         data.runtime.clone().spawn(async move {
-            let mut hasher = Sha1::new();
-            let mut progress = 0;
-            let mut reqwest = data.reqwest.clone();
-            let mut sources = sources.iter().cycle();
-            'retry: for retry in 0..3 {
-                let mut file = match OpenOptions::new().write(true).create(true).truncate(true).open(&*temp_path2).await {
-                    Ok(mut f) => {
-                        if let Err(err) = f.seek(SeekFrom::Start(progress)).await {
-                            error!("Proxy temp file seek fail: {}", err);
-                            continue 'retry;
-                        }
-                        f
-                    }
-                    Err(err) => {
-                        error!("Proxy temp file create fail: {}", err);
-                        continue 'retry;
-                    }
-                };
+            let downloaded = download(&data, &sources, &temp_path2, file_size, &tx2, &fanout2).await;
+            drop(permit); // Slow readers don't hold the fetch slot
+            let Some(downloaded) = downloaded else {
+                // Try remove from download state anyway
+                drop(state_guard);
+                return;
+            };
 
-                // Send request
-                let source = sources.next().unwrap();
-                let request = reqwest.get(source).send().await;
-                if let Err(ref err) = request {
-                    error!("Cache download fail: url={}, err={}", source, err);
+            if downloaded.hash == info2.hash() {
+                tx2.closed().await; // Wait all request done
+                remove_download_state(&data, &info2.hash(), &tx2);
+                if store {
+                    tx2.closed().await; // Wait again to avoid race conditions
+                    data.cache_manager.import_cache(&info2, &temp_path2).await;
+                }
+                return;
+            }
 
-                    // Disable proxy on third retry
-                    if retry == 1 && data.has_proxy {
-                        reqwest = create_http_client(Duration::from_secs(30), None);
-                    }
-                };
-
-                // Start download
-                let mut download = 0;
-                if let Ok(mut stream) = request.and_then(|r| r.error_for_status()).map(|r| r.bytes_stream()) {
-                    while let Some(bytes) = stream.next().await {
-                        let bytes = match &bytes {
-                            Ok(it) => it,
-                            Err(err) => {
-                                error!("Proxy download fail: url={}, err={}", source, err);
-                                continue 'retry;
-                            }
-                        };
-                        download += bytes.len() as u64;
+            // New requests start a new download instead of reading the corrupted file
+            error!("Cache hash mismatch: expected: {:x?}, got: {:x?}", info2.hash(), downloaded.hash);
+            remove_download_state(&data, &info2.hash(), &tx2);
 
-                        // Skip downloaded data
-                        if download <= progress {
-                            continue;
-                        }
-                        let write_size = (download - progress) as usize;
-                        let start = bytes.len() - write_size;
-                        let data = &bytes[start..];
-                        if let Err(err) = file.write_all(data).await {
-                            error!("Proxy temp file write fail: {}", err);
-                            break 'retry;
-                        }
-                        hasher.update(data);
-                        progress += write_size as u64;
-                        tx2.send_replace(progress);
-                    }
-                    if progress == file_size {
-                        if let Err(err) = file.flush().await {
-                            error!("Proxy temp file flush fail: {}", err);
-                            break 'retry;
-                        }
-                        let hash = hasher.finish();
-                        tx2.send_replace(progress);
-                        tx2.closed().await; // Wait all request done
-                        data.download_state.lock().remove(&info2.hash());
-                        if hash == info2.hash() {
-                            tx2.closed().await; // Wait again to avoid race conditions
-                            data.cache_manager.import_cache(&info2, &temp_path2).await;
-                        } else {
-                            error!("Cache hash mismatch: expected: {:x?}, got: {:x?}", info2.hash(), hash);
-                        }
-                        return;
-                    }
+            // Only blame the source when the whole file came from it, otherwise retry every source alone
+            let mut bad = Vec::new();
+            let tried = match downloaded.sources[..] {
+                [index] => {
+                    bad.push(sources[index].clone());
+                    vec![index]
+                }
+                _ => vec![],
+            };
+            let mut repaired = None;
+            if store {
+                match data.upstream.acquire().await {
+                    Ok(_permit) => repaired = failover(&data, &sources, &tried, file_size, info2.hash(), &mut bad).await,
+                    Err(_) => debug!("Upstream fetch queue timeout, skip failover: file={}", info2.file_id()),
                 }
             }
-
-            // Try remove from download state anyway
-            drop(state_guard);
+            report_bad_sources(&data, &bad, &file_index, &xres).await;
+            if let Some(path) = repaired {
+                data.cache_manager.import_cache(&info2, &path).await;
+            }
         });
 
-        (temp_path, tx.subscribe())
+        (temp_path, tx.subscribe(), fanout)
     };
 
     // Wait download start or 404
@@ -205,31 +269,58 @@ pub(super) async fn hath(
         return not_found();
     }
 
-    Response::builder()
-        .header(CONTENT_LENGTH, file_size)
-        .header(CONTENT_TYPE, HeaderValue::from_maybe_shared(info.mime_type().to_string()).unwrap())
-        .header(CACHE_HEADER.0, CACHE_HEADER.1)
-        .header(content_disposition.0, content_disposition.1)
+    builder
+        .header(CONTENT_LENGTH, end - start)
         .body(Body::from_stream(stream! {
-            let mut file = File::open(temp_path.as_ref()).await.unwrap();
-            let mut read_off = 0;
-            let mut write_off = *rx.borrow();
+            // Temp file is only opened when data is not in the shared buffer
+            let mut file: Option<File> = None;
+            let mut file_off = 0;
+            let mut read_off = start;
+            let mut write_off = (*rx.borrow()).min(end);
 
+            // Serve downloaded bytes first, then follow the download progress
             let wait_time = Duration::from_secs(30);
             'watch: while write_off > read_off || timeout(wait_time, rx.changed()).await.is_ok_and(|r| r.is_ok()) {
-                write_off = *rx.borrow();
+                write_off = (*rx.borrow()).min(end);
 
                 let mut buffer = BytesMut::with_capacity(64*1024); // 64 KiB
                 while write_off > read_off {
-                    buffer.reserve(64*1024);
-                    match file.read_buf(&mut buffer).await {
-                        Ok(s) => read_off += s as u64,
-                        Err(err) => yield Err(err)
+                    if let Some(data) = fanout.read(read_off, write_off - read_off) {
+                        read_off += data.len() as u64;
+                        yield Ok(data);
+                    } else {
+                        let file = match &mut file {
+                            Some(file) => file,
+                            None => match File::open(temp_path.as_ref()).await {
+                                Ok(f) => file.insert(f),
+                                Err(err) => {
+                                    yield Err(err);
+                                    return;
+                                }
+                            },
+                        };
+                        if file_off != read_off {
+                            match file.seek(SeekFrom::Start(read_off)).await {
+                                Ok(off) => file_off = off,
+                                Err(err) => {
+                                    yield Err(err);
+                                    return;
+                                }
+                            }
+                        }
+                        buffer.reserve(64*1024);
+                        match (&mut *file).take(write_off - read_off).read_buf(&mut buffer).await {
+                            Ok(s) => {
+                                read_off += s as u64;
+                                file_off = read_off;
+                            }
+                            Err(err) => yield Err(err)
+                        }
+                        yield Ok(buffer.split().freeze());
                     }
-                    yield Ok(buffer.split().freeze());
 
-                    // EOF
-                    if read_off == file_size {
+                    // End of range
+                    if read_off == end {
                         break 'watch;
                     }
                 }
@@ -237,3 +328,94 @@ pub(super) async fn hath(
         }))
         .unwrap()
 }
+
+/// Remove the download state of a file, only if it is still the download tracked by `progress`.
+///
+/// A newer download of the same file may have replaced it.
+fn remove_download_state(data: &AppState, hash: &[u8; 20], progress: &Arc<watch::Sender<u64>>) {
+    let mut download_state = data.download_state.lock();
+    if download_state.get(hash).is_some_and(|(_, tx, _)| Arc::ptr_eq(tx, progress)) {
+        download_state.remove(hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, http::header::IF_NONE_MATCH, Router};
+    use tempfile::tempdir;
+    use tokio::{runtime::Handle, sync::mpsc};
+
+    use super::*;
+    use crate::{
+        cache_manager::tests::{import_test_file, memory_cache_manager},
+        middleware::{AbuseGuard, AbuseThresholds},
+        route::{register_route, UpstreamLimit},
+        rpc::RPCClient,
+        util::create_http_client,
+    };
+
+    async fn test_router(dir: &std::path::Path) -> (Router, Arc<AppState>) {
+        let state = Arc::new(AppState {
+            runtime: Handle::current(),
+            reqwest: create_http_client(Duration::from_secs(30), None),
+            rpc: Arc::new(RPCClient::new(1, "testkey", false, 0, Duration::ZERO, 0, Duration::ZERO)),
+            download_state: Default::default(),
+            cache_manager: memory_cache_manager(dir, vec![]).await.unwrap(),
+            command_channel: mpsc::channel(1).0,
+            has_proxy: false,
+            source_health: Default::default(),
+            upstream: Arc::new(UpstreamLimit::new(0, Duration::from_secs(10), 0)),
+            bandwidth_limit: None,
+            abuse_guard: AbuseGuard::new(AbuseThresholds::default()),
+            head_warm_cache: false,
+            enable_stats: false,
+        });
+        (register_route(Router::new()).with_state(state.clone()), state)
+    }
+
+    fn file_uri(data: &AppState, info: &CacheFileInfo) -> String {
+        let time = data.rpc.get_timestemp();
+        let file_id = info.file_id();
+        let hash = string_to_hash(format!("{}-{}-{}-hotlinkthis", time, file_id, data.rpc.key()));
+        format!("/h/{}/keystamp={}-{};fileindex=1;xres=org/test.jpg", file_id, time, &hash[..10])
+    }
+
+    async fn get(router: &Router, uri: &str, headers: &[(HeaderName, &str)]) -> Response {
+        let mut req = Request::get(uri);
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        router.clone().oneshot(req.body(Body::empty()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn serve_from_memory_store() {
+        let dir = tempdir().unwrap();
+        let (router, state) = test_router(dir.path()).await;
+        let data = b"cache route test";
+        let info = import_test_file(&state.cache_manager, data).await;
+        let uri = file_uri(&state, &info);
+
+        let res = get(&router, &uri, &[]).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers().get(ETAG).unwrap().to_str().unwrap().to_string();
+        assert_eq!(&to_bytes(res.into_body(), usize::MAX).await.unwrap()[..], data);
+
+        let res = get(&router, &uri, &[(RANGE, "bytes=6-10")]).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(&to_bytes(res.into_body(), usize::MAX).await.unwrap()[..], &data[6..11]);
+
+        let res = get(&router, &uri, &[(IF_NONE_MATCH, &etag)]).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn reject_bad_keystamp() {
+        let dir = tempdir().unwrap();
+        let (router, state) = test_router(dir.path()).await;
+        let info = import_test_file(&state.cache_manager, b"keystamp test").await;
+        let uri = file_uri(&state, &info).replace("keystamp=", "keystamp=1");
+
+        assert_eq!(get(&router, &uri, &[]).await.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::Path,
+    slice,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+    time::Duration,
+};
+
+use axum::http::{
+    header::{CONTENT_RANGE, RANGE},
+    StatusCode,
+};
+use futures::{future::join_all, StreamExt};
+use log::{debug, error};
+use openssl::sha::Sha1;
+use parking_lot::Mutex;
+use reqwest::Url;
+use tempfile::TempPath;
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::watch,
+};
+
+use crate::{cache_manager::CacheFile, route::fanout::FanoutBuffer, util::create_http_client, AppState};
+
+/// Files smaller than this are downloaded from one source at a time.
+const SEGMENT_MIN_SIZE: u64 = 4 * 1024 * 1024; // 4MiB
+const MAX_SEGMENTS: usize = 4;
+const MAX_RETRY: usize = 3;
+
+pub(super) struct Downloaded {
+    pub hash: [u8; 20],
+    /// Index of sources which sent data of the file.
+    pub sources: Vec<usize>,
+}
+
+/// Download a cache miss into the temp file, `progress` is the length of the downloaded prefix.
+///
+/// Downloaded data is also published to `fanout`, so readers can skip reading the temp file.
+///
+/// Large files are split into byte ranges fetched from different sources concurrently.
+/// Return the hash of the file when fully downloaded.
+pub(super) async fn download(
+    data: &AppState,
+    sources: &[String],
+    path: &Path,
+    size: u64,
+    progress: &watch::Sender<u64>,
+    fanout: &FanoutBuffer,
+) -> Option<Downloaded> {
+    if sources.is_empty() {
+        return None;
+    }
+
+    let file = match OpenOptions::new().write(true).create(true).truncate(true).open(path).await {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Proxy temp file create fail: {}", err);
+            return None;
+        }
+    };
+
+    if sources.len() > 1 && size >= SEGMENT_MIN_SIZE {
+        // Preallocate, so segments can be written at their offsets
+        if let Err(err) = file.set_len(size).await {
+            error!("Proxy temp file allocate fail: {}", err);
+            return None;
+        }
+        drop(file);
+        return download_segments(data, sources, path, size, progress, fanout).await;
+    }
+
+    download_sequential(data, sources, file, size, progress, fanout).await
+}
+
+/// Download the whole file from one source, try next source on failure.
+async fn download_sequential(
+    data: &AppState,
+    sources: &[String],
+    mut file: File,
+    size: u64,
+    progress_tx: &watch::Sender<u64>,
+    fanout: &FanoutBuffer,
+) -> Option<Downloaded> {
+    let mut hasher = Sha1::new();
+    let mut progress = 0;
+    let mut reqwest = data.reqwest.clone();
+    let mut used = Vec::new();
+    'retry: for retry in 0..MAX_RETRY {
+        if let Err(err) = file.seek(SeekFrom::Start(progress)).await {
+            error!("Proxy temp file seek fail: {}", err);
+            continue 'retry;
+        }
+
+        // Send request, resume from downloaded data
+        let index = retry % sources.len();
+        let source = &sources[index];
+        let mut transfer = data.source_health.transfer(source);
+        let mut request = reqwest.get(source);
+        if progress > 0 {
+            request = request.header(RANGE, format!("bytes={}-", progress));
+        }
+        let request = request.send().await;
+        if let Err(ref err) = request {
+            error!("Cache download fail: url={}, err={}", source, err);
+
+            // Disable proxy on third retry
+            if retry == 1 && data.has_proxy {
+                reqwest = create_http_client(Duration::from_secs(30), None);
+            }
+        };
+
+        // Start download
+        if let Ok(response) = request.and_then(|r| r.error_for_status()) {
+            transfer.response();
+            let Some(skip) = response_skip(&response, progress) else {
+                error!("Proxy download invalid Content-Range: url={}", source);
+                continue 'retry;
+            };
+            if skip > 0 {
+                debug!("Source doesn't support range request, download from start: url={}", source);
+            }
+            let mut download = progress - skip; // Offset of the response body
+            let mut stream = response.bytes_stream();
+            while let Some(bytes) = stream.next().await {
+                let bytes = match &bytes {
+                    Ok(it) => it,
+                    Err(err) => {
+                        error!("Proxy download fail: url={}, err={}", source, err);
+                        continue 'retry;
+                    }
+                };
+                download += bytes.len() as u64;
+                transfer.received(bytes.len() as u64);
+                transfer.paused(data.upstream.throttle(bytes.len()).await);
+
+                // Skip downloaded data
+                if download <= progress {
+                    continue;
+                }
+                let write_size = (download - progress) as usize;
+                let start = bytes.len() - write_size;
+                let data = bytes.slice(start..);
+                if let Err(err) = file.write_all(&data).await {
+                    error!("Proxy temp file write fail: {}", err);
+                    transfer.cancel();
+                    break 'retry;
+                }
+                hasher.update(&data);
+                fanout.push(progress, data);
+                if !used.contains(&index) {
+                    used.push(index);
+                }
+                progress += write_size as u64;
+                progress_tx.send_replace(progress);
+            }
+            if progress == size {
+                if let Err(err) = file.flush().await {
+                    error!("Proxy temp file flush fail: {}", err);
+                    transfer.cancel();
+                    break 'retry;
+                }
+                progress_tx.send_replace(progress);
+                transfer.finish();
+                return Some(Downloaded {
+                    hash: hasher.finish(),
+                    sources: used,
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/// Download again from each source one by one until the hash matches, sources sent corrupted file are added to `bad`.
+///
+/// The file is downloaded to a new temp file, readers may still be reading the corrupted one.
+pub(super) async fn failover(
+    data: &AppState,
+    sources: &[String],
+    skip: &[usize],
+    size: u64,
+    hash: [u8; 20],
+    bad: &mut Vec<String>,
+) -> Option<TempPath> {
+    let path = data.cache_manager.create_temp_file().await;
+    for (index, source) in sources.iter().enumerate() {
+        if skip.contains(&index) {
+            continue;
+        }
+        debug!("Retry corrupted download from another source: url={}", source);
+        let (progress, _) = watch::channel(0);
+        let Some(downloaded) = download(data, slice::from_ref(source), &path, size, &progress, &FanoutBuffer::default()).await else {
+            continue;
+        };
+        if downloaded.hash == hash {
+            return Some(path);
+        }
+        error!(
+            "Cache hash mismatch: url={}, expected: {:x?}, got: {:x?}",
+            source, hash, downloaded.hash
+        );
+        bad.push(source.clone());
+    }
+    None
+}
+
+/// Report sources sent corrupted file to RPC server, same as failures of gallery downloader.
+pub(super) async fn report_bad_sources(data: &AppState, bad: &[String], file_index: &str, xres: &str) {
+    for source in bad {
+        data.source_health.record_failure(source);
+    }
+    let failures: Vec<String> = bad
+        .iter()
+        .filter_map(|source| Url::parse(source).ok())
+        .filter_map(|url| url.host_str().map(|host| format!("{}-{}-{}", host, file_index, xres)))
+        .collect();
+    data.rpc.dl_fails(&failures).await;
+}
+
+struct Segment {
+    range: Range<u64>,
+    written: AtomicU64,
+    /// Index of sources which sent data of the segment.
+    sources: Mutex<Vec<usize>>,
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        self.range.end - self.range.start
+    }
+}
+
+/// Download byte ranges of the file from different sources concurrently, fail if any segment fails.
+async fn download_segments(
+    data: &AppState,
+    sources: &[String],
+    path: &Path,
+    size: u64,
+    progress: &watch::Sender<u64>,
+    fanout: &FanoutBuffer,
+) -> Option<Downloaded> {
+    let count = sources.len().min(MAX_SEGMENTS);
+    let segment_size = size.div_ceil(count as u64);
+    let segments: Vec<Segment> = (0..count as u64)
+        .map(|i| Segment {
+            range: i * segment_size..((i + 1) * segment_size).min(size),
+            written: AtomicU64::new(0),
+            sources: Mutex::new(Vec::new()),
+        })
+        .collect();
+    debug!("Segmented download: size={}, segments={}", size, count);
+
+    // Readers only see the contiguous downloaded prefix
+    let update_progress = || {
+        let mut prefix = 0;
+        for segment in &segments {
+            let written = segment.written.load(Relaxed);
+            prefix += written;
+            if written < segment.len() {
+                break;
+            }
+        }
+        progress.send_if_modified(|progress| {
+            let changed = prefix > *progress;
+            *progress = prefix.max(*progress);
+            changed
+        });
+    };
+
+    // A failed segment stops the others, they end their transfers without blaming the hosts
+    let abort = &AtomicBool::new(false);
+    let update_progress = &update_progress;
+    let jobs = segments.iter().enumerate().map(|(index, segment)| async move {
+        let result = download_segment(data, sources, index, path, segment, fanout, update_progress, abort).await;
+        if result.is_err() {
+            abort.store(true, Relaxed);
+        }
+        result
+    });
+    join_all(jobs).await.into_iter().collect::<Result<Vec<_>, _>>().ok()?;
+
+    let mut used: Vec<usize> = segments.iter().flat_map(|s| s.sources.lock().clone()).collect();
+    used.sort_unstable();
+    used.dedup();
+
+    // Segments are written out of order, hash the assembled file
+    match CacheFile::Path(path.to_path_buf()).sha1().await {
+        Ok(hash) => Some(Downloaded { hash, sources: used }),
+        Err(err) => {
+            error!("Proxy temp file read fail: {}", err);
+            None
+        }
+    }
+}
+
+/// Download a byte range into the temp file, start from source `first` and try next source on failure.
+#[allow(clippy::too_many_arguments)]
+async fn download_segment<F: Fn()>(
+    data: &AppState,
+    sources: &[String],
+    first: usize,
+    path: &Path,
+    segment: &Segment,
+    fanout: &FanoutBuffer,
+    update_progress: &F,
+    abort: &AtomicBool,
+) -> Result<(), ()> {
+    let Segment {
+        range: segment,
+        written,
+        sources: used,
+    } = segment;
+    let len = segment.end - segment.start;
+    let mut file = match OpenOptions::new().write(true).open(path).await {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Proxy temp file open fail: {}", err);
+            return Err(());
+        }
+    };
+
+    let mut reqwest = data.reqwest.clone();
+    'retry: for retry in 0..MAX_RETRY {
+        if abort.load(Relaxed) {
+            return Err(());
+        }
+        let start = segment.start + written.load(Relaxed);
+        if let Err(err) = file.seek(SeekFrom::Start(start)).await {
+            error!("Proxy temp file seek fail: {}", err);
+            continue 'retry;
+        }
+
+        // Send request
+        let index = (first + retry) % sources.len();
+        let source = &sources[index];
+        let mut transfer = data.source_health.transfer(source);
+        let request = reqwest
+            .get(source)
+            .header(RANGE, format!("bytes={}-{}", start, segment.end - 1))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        let response = match request {
+            Ok(response) => response,
+            Err(err) => {
+                error!("Cache download fail: url={}, err={}", source, err);
+
+                // Disable proxy on third retry
+                if retry == 1 && data.has_proxy {
+                    reqwest = create_http_client(Duration::from_secs(30), None);
+                }
+                continue 'retry;
+            }
+        };
+
+        transfer.response();
+        let Some(mut skip) = response_skip(&response, start) else {
+            error!("Proxy download invalid Content-Range: url={}", source);
+            continue 'retry;
+        };
+        let mut stream = response.bytes_stream();
+        while let Some(bytes) = stream.next().await {
+            let bytes = match bytes {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    error!("Proxy download fail: url={}, err={}", source, err);
+                    continue 'retry;
+                }
+            };
+            if abort.load(Relaxed) {
+                transfer.cancel();
+                return Err(());
+            }
+            transfer.received(bytes.len() as u64);
+            transfer.paused(data.upstream.throttle(bytes.len()).await);
+            let skipped = skip.min(bytes.len() as u64);
+            skip -= skipped;
+            let remaining = len - written.load(Relaxed);
+            let data = bytes.slice(skipped as usize..);
+            let data = data.slice(..data.len().min(remaining as usize));
+            if data.is_empty() {
+                if remaining == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            // Flush before publishing progress, readers must not see the preallocated hole
+            let result = match file.write_all(&data).await {
+                Ok(_) => file.flush().await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                error!("Proxy temp file write fail: {}", err);
+                transfer.cancel();
+                return Err(());
+            }
+            fanout.push(segment.start + written.fetch_add(data.len() as u64, Relaxed), data);
+            {
+                let mut used = used.lock();
+                if !used.contains(&index) {
+                    used.push(index);
+                }
+            }
+            update_progress();
+        }
+
+        if written.load(Relaxed) == len {
+            transfer.finish();
+            return Ok(());
+        }
+    }
+
+    Err(())
+}
+
+/// Bytes to skip in the response body to reach `start`, None if the response is a range not starting at `start`.
+fn response_skip(response: &reqwest::Response, start: u64) -> Option<u64> {
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return Some(start); // Range not supported, whole file is sent
+    }
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (first, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    (first.trim().parse::<u64>().ok()? == start).then_some(0)
+}
@@ -0,0 +1,78 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+/// Max bytes kept in memory for each downloading file.
+const FANOUT_BUFFER_SIZE: usize = 4 * 1024 * 1024; // 4MiB
+
+/// Recent chunks of a downloading file shared by all readers of the download.
+///
+/// Readers serve data from memory while they keep up with the download,
+/// late joiners and readers fall behind the buffer read from the temp file instead.
+/// Chunks may arrive out of order from segmented downloads, the oldest pushed chunk is dropped first.
+#[derive(Default)]
+pub struct FanoutBuffer {
+    state: Mutex<FanoutState>,
+}
+
+#[derive(Default)]
+struct FanoutState {
+    /// File offset to chunk.
+    chunks: BTreeMap<u64, Bytes>,
+    /// Chunk offsets in push order.
+    order: VecDeque<u64>,
+    size: usize,
+}
+
+impl FanoutBuffer {
+    /// Add a downloaded chunk at `offset` of the file.
+    pub fn push(&self, offset: u64, data: Bytes) {
+        let mut state = self.state.lock();
+        if data.is_empty() || state.chunks.contains_key(&offset) {
+            return;
+        }
+        state.size += data.len();
+        state.chunks.insert(offset, data);
+        state.order.push_back(offset);
+
+        // Drop oldest chunks
+        while state.size > FANOUT_BUFFER_SIZE {
+            let Some(offset) = state.order.pop_front() else {
+                break;
+            };
+            if let Some(chunk) = state.chunks.remove(&offset) {
+                state.size -= chunk.len();
+            }
+        }
+    }
+
+    /// Data at `offset` up to `max` bytes, None if not in the buffer.
+    pub fn read(&self, offset: u64, max: u64) -> Option<Bytes> {
+        let state = self.state.lock();
+        let (start, chunk) = state.chunks.range(..=offset).next_back()?;
+        let from = (offset - start) as usize;
+        if from >= chunk.len() {
+            return None;
+        }
+        let to = chunk.len().min(from.saturating_add(max as usize));
+        Some(chunk.slice(from..to))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn out_of_order_segments() {
+        let fanout = FanoutBuffer::default();
+        fanout.push(100, Bytes::from_static(b"segment1"));
+        fanout.push(0, Bytes::from_static(b"segment0"));
+
+        assert_eq!(&fanout.read(0, 100).unwrap()[..], b"segment0");
+        assert_eq!(&fanout.read(102, 3).unwrap()[..], b"gme");
+        assert!(fanout.read(8, 100).is_none());
+        assert!(fanout.read(108, 100).is_none());
+    }
+}
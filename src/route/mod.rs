@@ -1,21 +1,46 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, ops::Range, sync::Arc};
 
 use axum::{
     body::Body,
-    http::{header::LOCATION, Response, StatusCode},
+    http::{
+        header::{IF_MATCH, IF_NONE_MATCH, IF_RANGE, LOCATION, RANGE, RETRY_AFTER},
+        HeaderMap, HeaderValue, Response, StatusCode,
+    },
     response::{Html, IntoResponse},
     routing::get,
     Router,
 };
 
+pub use crate::route::{fanout::FanoutBuffer, upstream::UpstreamLimit};
 use crate::{
-    route::{cache::hath, server_command::servercmd, speed_test::speedtest},
+    route::{cache::hath, server_command::servercmd, speed_test::speedtest, stats::bans},
     AppState,
 };
 
+/// Result of parsing a `Range` request header against a content length.
+enum ByteRange {
+    /// No usable range, serve the whole content.
+    Full,
+    Partial(Range<u64>),
+    Unsatisfiable,
+}
+
+/// Result of evaluating conditional request headers against the content ETag.
+enum Precondition {
+    Pass,
+    /// `If-None-Match` matched, reply 304.
+    NotModified,
+    /// `If-Match` didn't match, reply 412.
+    Failed,
+}
+
 mod cache;
+mod download;
+mod fanout;
 mod server_command;
 mod speed_test;
+mod stats;
+mod upstream;
 
 pub fn register_route(router: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
     router
@@ -24,6 +49,7 @@ pub fn register_route(router: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
         .route("/servercmd/:command/:additional/:time/:key", get(servercmd).head(servercmd))
         .route("/t/:size/:time/:hash/:random", get(speedtest).head(speedtest))
         .route("/h/:fileid/:additional/*filename", get(hath).head(hath))
+        .route("/stats/bans", get(bans))
         .fallback(get(default).head(default))
 }
 
@@ -47,6 +73,15 @@ fn not_found() -> Response<Body> {
     (StatusCode::NOT_FOUND, "An error has occurred. (404)").into_response()
 }
 
+fn service_unavailable() -> Response<Body> {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(RETRY_AFTER, "5")],
+        "An error has occurred. (503)",
+    )
+        .into_response()
+}
+
 fn parse_additional(additional: &str) -> HashMap<&str, &str> {
     let mut map = HashMap::new();
     for kv in additional.split(';') {
@@ -62,3 +97,70 @@ fn parse_additional(additional: &str) -> HashMap<&str, &str> {
 
     map
 }
+
+/// Parse single range `Range` header, multiple or malformed ranges are ignored and served in full.
+fn parse_range(value: Option<&HeaderValue>, len: u64) -> ByteRange {
+    let Some(spec) = value.and_then(|v| v.to_str().ok()).and_then(|v| v.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    if start.is_empty() {
+        // Suffix range
+        return match end.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial(len.saturating_sub(n)..len),
+            Err(_) => ByteRange::Full,
+        };
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return ByteRange::Full;
+    };
+    let end = match end {
+        "" => u64::MAX,
+        end => match end.parse::<u64>() {
+            Ok(end) if end >= start => end,
+            _ => return ByteRange::Full,
+        },
+    };
+    if start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+
+    ByteRange::Partial(start..end.min(len - 1) + 1)
+}
+
+/// Evaluate `If-Match` and `If-None-Match` with the strong `etag`, and drop `Range` when `If-Range` doesn't match.
+fn check_precondition(headers: &mut HeaderMap, etag: &str) -> Precondition {
+    // `If-Match` uses strong comparison, a weak tag never matches.
+    // `If-None-Match` uses weak comparison, the `W/` prefix is ignored.
+    let matches = |value: &HeaderValue, weak: bool| {
+        value.to_str().is_ok_and(|list| {
+            list.split(',').map(str::trim).any(|tag| {
+                let tag = if weak { tag.strip_prefix("W/").unwrap_or(tag) } else { tag };
+                tag == "*" || tag == etag
+            })
+        })
+    };
+
+    if headers.get(IF_MATCH).is_some_and(|v| !matches(v, false)) {
+        return Precondition::Failed;
+    }
+    if headers.get(IF_NONE_MATCH).is_some_and(|v| matches(v, true)) {
+        return Precondition::NotModified;
+    }
+    if let Some(value) = headers.remove(IF_RANGE) {
+        // Only entity tag validator is supported, a date never matches
+        if value.to_str().ok().map(str::trim) != Some(etag) {
+            headers.remove(RANGE);
+        }
+    }
+    Precondition::Pass
+}
@@ -0,0 +1,35 @@
+use std::{fmt::Write, sync::Arc};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    route::{default, forbidden},
+    server::ClientAddr,
+    AppState,
+};
+
+/// Current IP bans of the abuse guard, only for local requests when stats are enabled.
+pub(super) async fn bans(addr: ClientAddr, data: State<Arc<AppState>>) -> Response {
+    if !data.enable_stats {
+        return default().await.into_response();
+    }
+    if !addr.ip().is_loopback() {
+        return forbidden();
+    }
+
+    let mut text = String::new();
+    for ban in data.abuse_guard.ban_list() {
+        let _ = writeln!(
+            text,
+            "ip={}, strikes={}, remaining={}s, hits={}",
+            ban.ip,
+            ban.strikes,
+            ban.remaining.as_secs(),
+            ban.hits
+        );
+    }
+    text.into_response()
+}
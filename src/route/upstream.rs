@@ -0,0 +1,66 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep, timeout},
+};
+
+use crate::util::TokenBucket;
+
+/// Global limits of upstream fetches for cache misses, so a burst of misses doesn't saturate the downlink.
+pub struct UpstreamLimit {
+    /// Concurrent fetch slots, None for unlimited.
+    permits: Option<Arc<Semaphore>>,
+    /// Max time a cache miss waits for a fetch slot.
+    queue_timeout: Duration,
+    /// Inbound bytes/sec of all fetches, 0 to disable.
+    rate: u64,
+    bucket: TokenBucket,
+    rejected: AtomicU64,
+}
+
+impl UpstreamLimit {
+    pub fn new(max_fetches: usize, queue_timeout: Duration, rate: u64) -> Self {
+        Self {
+            permits: (max_fetches > 0).then(|| Arc::new(Semaphore::new(max_fetches))),
+            queue_timeout,
+            rate,
+            bucket: TokenBucket::default(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Wait for a fetch slot, Err if the queue timed out.
+    pub(super) async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        let Some(permits) = &self.permits else {
+            return Ok(None);
+        };
+        match timeout(self.queue_timeout, permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(Some(permit)),
+            _ => {
+                self.rejected.fetch_add(1, Relaxed);
+                Err(())
+            }
+        }
+    }
+
+    /// Wait until received data fits in the bandwidth budget, return the time waited.
+    pub(super) async fn throttle(&self, size: usize) -> Duration {
+        let wait = self.bucket.take(self.rate, size);
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+        wait
+    }
+
+    /// Number of cache misses rejected by queue timeout.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Relaxed)
+    }
+}
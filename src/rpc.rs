@@ -19,9 +19,10 @@ use openssl::{
     pkcs12::{ParsedPkcs12_2, Pkcs12},
     provider::Provider,
 };
-use parking_lot::{RwLock, RwLockUpgradableReadGuard};
+use parking_lot::{Mutex, RwLock, RwLockUpgradableReadGuard};
 use rand::prelude::SliceRandom;
 use reqwest::{IntoUrl, Url};
+use tokio::{sync::Semaphore, time::timeout};
 
 use crate::{
     error::Error,
@@ -31,6 +32,7 @@ use crate::{
 
 const API_VERSION: i32 = 169; // For server check capabilities.
 const DEFAULT_SERVER: &str = "rpc.hentaiathome.net";
+const SRFETCH_FAILED_MAX: usize = 1024;
 
 type RequestError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -43,6 +45,25 @@ pub struct RPCClient {
     rpc_servers: RwLock<Vec<String>>,
     running: AtomicBool,
     settings: Arc<Settings>,
+    srfetch: SrFetchGuard,
+}
+
+/// Protect RPC server from lookup storm of broken files.
+struct SrFetchGuard {
+    /// Failed file ID and when it expires, 0 TTL to disable.
+    failed: Mutex<HashMap<String, Instant>>,
+    failure_ttl: Duration,
+    /// Concurrent lookup limit, None for unlimited.
+    permits: Option<Semaphore>,
+    queue_timeout: Duration,
+    suppressed: AtomicU64,
+}
+
+pub enum SrFetchError {
+    /// File not found or lookup failed.
+    NotFound,
+    /// Too many lookups in progress, client should retry later.
+    Busy,
 }
 
 pub struct Settings {
@@ -52,6 +73,7 @@ pub struct Settings {
     max_connection: AtomicU64,
     disable_ip_check: bool,
     disable_lru_cache: AtomicBool,
+    static_range: RwLock<Vec<String>>,
 }
 
 pub struct InitSettings {
@@ -77,6 +99,16 @@ impl InitSettings {
     pub fn static_range(&self) -> Vec<String> {
         self.static_range.clone()
     }
+
+    #[cfg(test)]
+    pub fn new_for_test(static_range: Vec<String>, verify_cache: bool) -> Self {
+        Self {
+            client_port: 0,
+            client_host: String::new(),
+            verify_cache,
+            static_range,
+        }
+    }
 }
 
 impl Settings {
@@ -94,6 +126,11 @@ impl Settings {
         }
     }
 
+    /// Bandwidth limit in bytes/sec, 0 means unlimited.
+    pub fn throttle_bytes(&self) -> u64 {
+        self.throttle_bytes.load(Ordering::Relaxed)
+    }
+
     pub fn disable_logging(&self) -> bool {
         self.disable_logging.load(Ordering::Relaxed)
     }
@@ -102,6 +139,11 @@ impl Settings {
         self.disable_lru_cache.load(Ordering::Relaxed)
     }
 
+    /// Current assigned static ranges, updated by login and settings refresh.
+    pub fn static_range(&self) -> Vec<String> {
+        self.static_range.read().clone()
+    }
+
     fn update(&self, settings: HashMap<&str, &str>) {
         if let Some(size) = settings.get("disklimit_bytes").and_then(|s| s.parse().ok()) {
             self.size_limit.store(size, Ordering::Relaxed);
@@ -116,11 +158,23 @@ impl Settings {
 
         let use_less_memory = settings.get("use_less_memory").and_then(|s| s.parse().ok()).unwrap_or(false);
         self.disable_lru_cache.store(use_less_memory, Ordering::Relaxed);
+
+        if let Some(ranges) = settings.get("static_ranges") {
+            *self.static_range.write() = ranges.split(';').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect();
+        }
     }
 }
 
 impl RPCClient {
-    pub fn new(id: i32, key: &str, disable_ip_check: bool, max_connection: u64) -> Self {
+    pub fn new(
+        id: i32,
+        key: &str,
+        disable_ip_check: bool,
+        max_connection: u64,
+        srfetch_failure_ttl: Duration,
+        srfetch_concurrency: usize,
+        srfetch_queue_timeout: Duration,
+    ) -> Self {
         if disable_ip_check {
             warn!("Disable server command ip check!");
         }
@@ -143,7 +197,15 @@ impl RPCClient {
                 max_connection: AtomicU64::new(max_connection),
                 disable_ip_check,
                 disable_lru_cache: AtomicBool::new(false),
+                static_range: RwLock::new(vec![]),
             }),
+            srfetch: SrFetchGuard {
+                failed: Mutex::new(HashMap::new()),
+                failure_ttl: srfetch_failure_ttl,
+                permits: (srfetch_concurrency > 0).then(|| Semaphore::new(srfetch_concurrency)),
+                queue_timeout: srfetch_queue_timeout,
+                suppressed: AtomicU64::new(0),
+            },
         }
     }
 
@@ -186,10 +248,6 @@ impl RPCClient {
                 .ok_or_else(|| Error::InitSettingsMissing("host".to_string()))?
                 .to_owned();
             let verify_cache = map.get("verify_cache").and_then(|s| s.parse().ok()).unwrap_or(false);
-            let static_range = map
-                .get("static_ranges")
-                .map(|s| s.split(';').map(|s| s.to_string()).collect())
-                .unwrap_or_default();
 
             self.update_settings(map);
 
@@ -197,7 +255,7 @@ impl RPCClient {
                 client_port,
                 client_host,
                 verify_cache,
-                static_range,
+                static_range: self.settings.static_range(),
             })
         } else {
             let err = Error::ApiResponseFail {
@@ -327,15 +385,50 @@ The program will now terminate.
         self.settings.disable_ip_check || self.rpc_servers.read().iter().any(|s| s == ip)
     }
 
-    pub async fn sr_fetch(&self, file_index: &str, xres: &str, file_id: &str) -> Option<Vec<String>> {
+    pub async fn sr_fetch(&self, file_index: &str, xres: &str, file_id: &str) -> Result<Vec<String>, SrFetchError> {
+        let guard = &self.srfetch;
+        // Recently failed
+        if guard.failed.lock().get(file_id).is_some_and(|expire| *expire > Instant::now()) {
+            guard.suppressed.fetch_add(1, Ordering::Relaxed);
+            return Err(SrFetchError::NotFound);
+        }
+        let _permit = match &guard.permits {
+            Some(permits) => match timeout(guard.queue_timeout, permits.acquire()).await {
+                Ok(Ok(permit)) => Some(permit),
+                _ => {
+                    debug!("Too many srfetch in progress, skip: file={}", file_id);
+                    guard.suppressed.fetch_add(1, Ordering::Relaxed);
+                    return Err(SrFetchError::Busy);
+                }
+            },
+            None => None,
+        };
+
         let add = format!("{file_index};{xres};{file_id}");
         if let Ok(res) = self.send_action("srfetch", Some(&add)).await {
             if res.is_ok() {
-                return Some(res.data);
+                return Ok(res.data);
             }
         }
 
-        None
+        if !guard.failure_ttl.is_zero() {
+            let now = Instant::now();
+            let mut failed = guard.failed.lock();
+            failed.retain(|_, expire| *expire > now);
+            if failed.len() >= SRFETCH_FAILED_MAX {
+                // Evict the one expiring soonest
+                if let Some(key) = failed.iter().min_by_key(|(_, expire)| **expire).map(|(key, _)| key.clone()) {
+                    failed.remove(&key);
+                }
+            }
+            failed.insert(file_id.to_string(), now + guard.failure_ttl);
+        }
+        Err(SrFetchError::NotFound)
+    }
+
+    /// Number of srfetch skipped by negative cache or concurrency limit.
+    pub fn srfetch_suppressed(&self) -> u64 {
+        self.srfetch.suppressed.load(Ordering::Relaxed)
     }
 
     pub async fn dl_fails<T: AsRef<str>>(&self, failures: &Vec<T>) {
@@ -0,0 +1,200 @@
+use std::{
+    ffi::{c_int, c_void},
+    io::{Error, ErrorKind},
+    net::{Ipv4Addr, Shutdown, TcpListener as StdTcpListener, TcpStream as StdTcpStream},
+    os::fd::AsRawFd,
+    pin::Pin,
+    ptr::null_mut,
+    task::{ready, Context, Poll},
+};
+
+use foreign_types::ForeignType;
+use log::debug;
+use openssl::{error::ErrorStack, ssl::Ssl};
+use openssl_sys::{
+    BIO_ctrl, BIO_new_socket, ERR_clear_error, SSL_accept, SSL_get_error, SSL_get_wbio, SSL_read_ex, SSL_set_bio, SSL_shutdown,
+    SSL_write_ex, SSL, SSL_ERROR_SYSCALL, SSL_ERROR_WANT_READ, SSL_ERROR_WANT_WRITE, SSL_ERROR_ZERO_RETURN,
+};
+use tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+// Read from the linked OpenSSL headers by build script: SSL_OP_ENABLE_KTLS, BIO_CTRL_GET_KTLS_SEND, BIO_NOCLOSE
+include!(concat!(env!("OUT_DIR"), "/ktls_consts.rs"));
+
+/// Check the kernel accepts TLS ULP on a loopback connection, same as OpenSSL does before enabling kTLS.
+///
+/// Connections still check `BIO_get_ktls_send` after handshake, as the cipher may not be offloaded.
+pub(super) fn probe() -> Result<(), Error> {
+    let listener = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let stream = StdTcpStream::connect(listener.local_addr()?)?;
+    let _peer = listener.accept()?;
+    let ulp = b"tls";
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_TCP,
+            libc::TCP_ULP,
+            ulp.as_ptr() as *const c_void,
+            ulp.len() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// TLS stream on OpenSSL socket BIO, so OpenSSL can hand the session keys to kernel (kTLS) after handshake.
+///
+/// Responses are still written through hyper, with kTLS send enabled the kernel encrypts them.
+pub(super) struct KtlsStream {
+    io: AsyncFd<StdTcpStream>,
+    ssl: Ssl,
+}
+
+impl KtlsStream {
+    pub async fn accept(ssl: Ssl, stream: TcpStream) -> Result<Self, Error> {
+        let stream = stream.into_std()?;
+        // Same as SSL_set_fd, the socket is owned by `io`
+        let bio = unsafe { BIO_new_socket(stream.as_raw_fd(), BIO_NOCLOSE) };
+        if bio.is_null() {
+            return Err(Error::other(ErrorStack::get()));
+        }
+        unsafe { SSL_set_bio(ssl.as_ptr(), bio, bio) };
+        let new = Self {
+            io: AsyncFd::new(stream)?,
+            ssl,
+        };
+
+        let mut want_write = false;
+        loop {
+            let mut guard = if want_write {
+                new.io.writable().await?
+            } else {
+                new.io.readable().await?
+            };
+            match guard.try_io(|_| new.ssl_call(&mut want_write, |ssl| unsafe { SSL_accept(ssl) as isize })) {
+                Ok(Ok(_)) => break,
+                Ok(Err(err)) => return Err(err),
+                Err(_) => continue,
+            }
+        }
+
+        if !new.ktls_send() {
+            debug!("Kernel TLS send not enabled for connection, cipher may not be supported");
+        }
+        Ok(new)
+    }
+
+    /// Wait until the socket has data, for first byte timeout.
+    pub async fn readable(&self) -> Result<(), Error> {
+        self.io.readable().await?.retain_ready();
+        Ok(())
+    }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+        self.io.get_ref().set_nodelay(nodelay)
+    }
+
+    fn ktls_send(&self) -> bool {
+        unsafe { BIO_ctrl(SSL_get_wbio(self.ssl.as_ptr()), BIO_CTRL_GET_KTLS_SEND, 0, null_mut()) > 0 }
+    }
+
+    /// Run a SSL call, map want read/write to `WouldBlock` and remember the wanted direction.
+    fn ssl_call<F: FnOnce(*mut SSL) -> isize>(&self, want_write: &mut bool, f: F) -> Result<usize, Error> {
+        unsafe { ERR_clear_error() };
+        let ret = f(self.ssl.as_ptr());
+        if ret > 0 {
+            return Ok(ret as usize);
+        }
+
+        match unsafe { SSL_get_error(self.ssl.as_ptr(), ret as c_int) } {
+            SSL_ERROR_WANT_READ => {
+                *want_write = false;
+                Err(ErrorKind::WouldBlock.into())
+            }
+            SSL_ERROR_WANT_WRITE => {
+                *want_write = true;
+                Err(ErrorKind::WouldBlock.into())
+            }
+            SSL_ERROR_ZERO_RETURN => Ok(0), // Closed by peer
+            SSL_ERROR_SYSCALL => match Error::last_os_error() {
+                err if err.raw_os_error() == Some(0) => Err(ErrorKind::UnexpectedEof.into()),
+                err => Err(err),
+            },
+            _ => Err(Error::other(ErrorStack::get())),
+        }
+    }
+}
+
+impl AsyncRead for KtlsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        let mut want_write = false;
+        loop {
+            let mut guard = if want_write {
+                ready!(this.io.poll_write_ready(cx))?
+            } else {
+                ready!(this.io.poll_read_ready(cx))?
+            };
+            let unfilled = buf.initialize_unfilled();
+            let mut read = 0;
+            let result = guard.try_io(|_| {
+                this.ssl_call(&mut want_write, |ssl| unsafe {
+                    SSL_read_ex(ssl, unfilled.as_mut_ptr() as *mut c_void, unfilled.len(), &mut read) as isize
+                })
+            });
+            match result {
+                Ok(Ok(_)) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for KtlsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        let this = self.get_mut();
+        let mut want_write = true;
+        loop {
+            let mut guard = if want_write {
+                ready!(this.io.poll_write_ready(cx))?
+            } else {
+                ready!(this.io.poll_read_ready(cx))?
+            };
+            let mut written = 0;
+            let result = guard.try_io(|_| {
+                this.ssl_call(&mut want_write, |ssl| unsafe {
+                    SSL_write_ex(ssl, buf.as_ptr() as *const c_void, buf.len(), &mut written) as isize
+                })
+            });
+            match result {
+                Ok(Ok(_)) => return Poll::Ready(Ok(written)),
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_) => continue,
+            }
+        }
+    }
+
+    /// Writes go to the socket directly, nothing is buffered.
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+
+        // Best effort close notify, don't wait for peer.
+        unsafe {
+            ERR_clear_error();
+            SSL_shutdown(self.ssl.as_ptr());
+        }
+        Poll::Ready(self.io.get_ref().shutdown(Shutdown::Write))
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    convert::Infallible,
     net::SocketAddr,
     ops::Deref,
     pin::Pin,
@@ -10,9 +11,9 @@ use std::{
 };
 
 use arc_swap::ArcSwap;
-use axum::{async_trait, extract::FromRequestParts, http::request::Parts, Extension, Router};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts, response::Response, Extension, Router};
 use futures::pin_mut;
-use hyper::server::conn::http1;
+use hyper::{body::Incoming, server::conn::http1, Request};
 use hyper_util::rt::{TokioIo, TokioTimer};
 use log::info;
 use openssl::{
@@ -20,7 +21,7 @@ use openssl::{
     ssl::{Ssl, SslAcceptor},
 };
 use tokio::{
-    io::AsyncWriteExt,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     net::{TcpListener, TcpSocket, TcpStream},
     sync::{watch, Notify},
     task::JoinHandle,
@@ -31,6 +32,8 @@ use tower::{Layer, Service};
 
 use crate::{middleware, route, server::ssl::create_ssl_acceptor, AppState};
 
+#[cfg(ktls)]
+mod ktls;
 pub mod ssl;
 
 pub struct Server {
@@ -42,11 +45,14 @@ pub struct ServerHandle {
     shutdown: AtomicBool,
     shutdown_notify: Notify,
     ssl_acceptor: ArcSwap<SslAcceptor>,
+    ktls: bool,
 }
 
 impl Server {
-    pub fn new(port: u16, cert: ParsedPkcs12_2, data: AppState) -> Self {
-        let handle = Arc::new(ServerHandle::new(create_ssl_acceptor(cert)));
+    /// Create server, `ktls` enables kernel TLS offload on Linux.
+    pub fn new(port: u16, cert: ParsedPkcs12_2, data: AppState, ktls: bool) -> Self {
+        let ktls = ktls && ktls_supported();
+        let handle = Arc::new(ServerHandle::new(create_ssl_acceptor(cert, ktls), ktls));
         let mut listener = bind(SocketAddr::from(([0, 0, 0, 0], port)));
 
         let mut http = http1::Builder::new();
@@ -82,8 +88,29 @@ impl Server {
                 let handle = handle.clone();
                 let http = http.clone();
                 let service = Extension(ClientAddr(addr)).layer(router.clone());
-                let mut shutdown_rx = shutdown_tx.subscribe();
+                let shutdown_rx = shutdown_tx.subscribe();
                 tokio::spawn(async move {
+                    #[cfg(ktls)]
+                    if handle.ktls {
+                        // TLS handshake
+                        let ssl = Ssl::new(handle.ssl_acceptor.load().context()).unwrap();
+                        let stream = match timeout(Duration::from_secs(10), ktls::KtlsStream::accept(ssl, stream)).await {
+                            Ok(Ok(stream)) => stream,
+                            _ => return, // Handshake timeout or error
+                        };
+
+                        // Disable nodelay after handshake
+                        let _ = stream.set_nodelay(false);
+
+                        // First byte timeout
+                        if timeout(Duration::from_secs(10), stream.readable()).await.is_err() {
+                            return;
+                        }
+
+                        serve_connection(stream, http, service, shutdown_rx).await;
+                        return;
+                    }
+
                     // TLS handshake
                     let mut ssl_stream = SslStream::new(Ssl::new(handle.ssl_acceptor.load().context()).unwrap(), stream).unwrap();
                     match timeout(Duration::from_secs(10), SslStream::accept(Pin::new(&mut ssl_stream))).await {
@@ -100,18 +127,7 @@ impl Server {
                         return;
                     }
 
-                    // Process request
-                    let stream = TokioIo::new(ssl_stream); // Tokio to hyper trait
-                    let service = hyper::service::service_fn(move |r| service.clone().call(r)); // Tower to hyper trait
-                    let fut = timeout(Duration::from_secs(181), http.serve_connection(stream, service));
-                    pin_mut!(fut);
-                    tokio::select! {
-                        biased;
-                        _ = &mut fut => (),
-                        _ = shutdown_rx.changed() => { // Graceful shutdown
-                            let _ = timeout(Duration::from_secs(10), fut).await;
-                        },
-                    };
+                    serve_connection(ssl_stream, http, service, shutdown_rx).await;
                 });
             }
 
@@ -139,16 +155,17 @@ impl Server {
 }
 
 impl ServerHandle {
-    fn new(ssl: SslAcceptor) -> Self {
+    fn new(ssl: SslAcceptor, ktls: bool) -> Self {
         Self {
             shutdown: AtomicBool::new(false),
             shutdown_notify: Notify::new(),
             ssl_acceptor: ArcSwap::new(Arc::new(ssl)),
+            ktls,
         }
     }
 
     pub fn update_cert(&self, cert: ParsedPkcs12_2) {
-        let acceptor = create_ssl_acceptor(cert);
+        let acceptor = create_ssl_acceptor(cert, self.ktls);
         self.ssl_acceptor.store(Arc::new(acceptor));
     }
 
@@ -184,6 +201,43 @@ where
     }
 }
 
+/// Process requests on a TLS connection until done, timeout or server shutdown.
+async fn serve_connection<I, S>(io: I, http: http1::Builder, service: S, mut shutdown_rx: watch::Receiver<()>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let stream = TokioIo::new(io); // Tokio to hyper trait
+    let service = hyper::service::service_fn(move |r| service.clone().call(r)); // Tower to hyper trait
+    let fut = timeout(Duration::from_secs(181), http.serve_connection(stream, service));
+    pin_mut!(fut);
+    tokio::select! {
+        biased;
+        _ = &mut fut => (),
+        _ = shutdown_rx.changed() => { // Graceful shutdown
+            let _ = timeout(Duration::from_secs(10), fut).await;
+        },
+    };
+}
+
+#[cfg(ktls)]
+fn ktls_supported() -> bool {
+    match ktls::probe() {
+        Ok(_) => true,
+        Err(err) => {
+            log::warn!("Kernel TLS is not available, fallback to userspace TLS: err={}", err);
+            false
+        }
+    }
+}
+
+#[cfg(not(ktls))]
+fn ktls_supported() -> bool {
+    log::warn!("Kernel TLS is only supported on Linux with the vendored OpenSSL, ignore it");
+    false
+}
+
 fn bind(addr: SocketAddr) -> TcpListener {
     let socket = match addr {
         SocketAddr::V4(_) => TcpSocket::new_v4(),
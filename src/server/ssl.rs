@@ -14,11 +14,17 @@ use openssl::{
 use reqwest::Url;
 use tokio::time::{sleep_until, Instant};
 
-pub fn create_ssl_acceptor(cert: ParsedPkcs12_2) -> SslAcceptor {
+#[cfg_attr(not(ktls), allow(unused_variables))]
+pub fn create_ssl_acceptor(cert: ParsedPkcs12_2, ktls: bool) -> SslAcceptor {
     // TODO error handle
     let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls_server()).unwrap();
     builder.clear_options(SslOptions::NO_TLSV1_3);
     builder.set_options(SslOptions::NO_RENEGOTIATION | SslOptions::ENABLE_MIDDLEBOX_COMPAT);
+    #[cfg(ktls)]
+    if ktls {
+        // Only take effect on socket BIO, see `KtlsStream`
+        builder.set_options(SslOptions::from_bits_retain(super::ktls::SSL_OP_ENABLE_KTLS as _));
+    }
     builder.set_mode(SslMode::RELEASE_BUFFERS);
     builder.set_session_cache_mode(SslSessionCacheMode::OFF); // Disable session ID resumption
     let _ = builder.set_num_tickets(1);
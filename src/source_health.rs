@@ -0,0 +1,233 @@
+use std::{collections::HashMap, time::Duration};
+
+use log::info;
+use parking_lot::Mutex;
+use reqwest::Url;
+use tokio::time::Instant;
+
+/// Weight of a new sample in the moving averages.
+const ALPHA: f64 = 0.2;
+/// Error rate halves every 10min without new samples.
+const ERROR_HALF_LIFE: Duration = Duration::from_secs(600);
+/// Forget hosts not used for a day.
+const IDLE_TTL: Duration = Duration::from_secs(86400);
+/// Smaller transfers are dominated by latency, don't count their throughput.
+const THROUGHPUT_MIN_BYTES: u64 = 64 * 1024; // 64KiB
+/// Size used to weigh latency against throughput.
+const REFERENCE_SIZE: f64 = 1024.0 * 1024.0; // 1MiB
+const DEFAULT_LATENCY: f64 = 0.5;
+const DEFAULT_THROUGHPUT: f64 = 1024.0 * 1024.0; // 1MiB/s
+
+/// Health of upstream hosts measured from cache miss and gallery downloads.
+#[derive(Default)]
+pub struct SourceHealth {
+    hosts: Mutex<HashMap<String, HostStats>>,
+}
+
+struct HostStats {
+    /// Seconds until response headers received.
+    latency: Option<f64>,
+    /// Bytes per second of the response body.
+    throughput: Option<f64>,
+    error_rate: f64,
+    requests: u64,
+    failures: u64,
+    last_update: Instant,
+}
+
+/// Snapshot of a host.
+pub struct HostHealth {
+    pub host: String,
+    pub latency: Option<Duration>,
+    pub throughput: Option<u64>,
+    pub error_rate: f64,
+    pub requests: u64,
+    pub failures: u64,
+}
+
+impl HostStats {
+    fn decayed_error_rate(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.last_update).as_secs_f64();
+        self.error_rate * 0.5f64.powf(elapsed / ERROR_HALF_LIFE.as_secs_f64())
+    }
+
+    /// Expected seconds to fetch a reference sized file, including retries.
+    fn cost(&self, now: Instant) -> f64 {
+        let time = self.latency.unwrap_or(DEFAULT_LATENCY) + REFERENCE_SIZE / self.throughput.unwrap_or(DEFAULT_THROUGHPUT);
+        time / (1.0 - self.decayed_error_rate(now)).max(0.05)
+    }
+}
+
+fn average(old: Option<f64>, sample: f64) -> f64 {
+    old.map_or(sample, |old| old + ALPHA * (sample - old))
+}
+
+fn host_of(url: &str) -> Option<String> {
+    Url::parse(url).ok()?.host_str().map(str::to_string)
+}
+
+impl SourceHealth {
+    /// Start measuring a request to `url`.
+    pub fn transfer(&self, url: &str) -> Transfer<'_> {
+        Transfer {
+            health: self,
+            host: host_of(url),
+            start: Instant::now(),
+            response: None,
+            bytes: 0,
+            paused: Duration::ZERO,
+            finished: false,
+        }
+    }
+
+    /// Count a failure of a source outside of a transfer, like a corrupted file.
+    pub fn record_failure(&self, url: &str) {
+        if let Some(host) = host_of(url) {
+            self.update(host, false, |_| {});
+        }
+    }
+
+    fn update<F: FnOnce(&mut HostStats)>(&self, host: String, success: bool, f: F) {
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock();
+        let stats = hosts.entry(host).or_insert_with(|| HostStats {
+            latency: None,
+            throughput: None,
+            error_rate: 0.0,
+            requests: 0,
+            failures: 0,
+            last_update: now,
+        });
+        let error = if success { 0.0 } else { 1.0 };
+        stats.error_rate = average(Some(stats.decayed_error_rate(now)), error);
+        stats.requests += 1;
+        stats.failures += u64::from(!success);
+        stats.last_update = now;
+        f(stats);
+    }
+
+    /// Reorder sources so the healthiest is tried first.
+    ///
+    /// Hosts without stats are tried first in their original order, so they get measured.
+    pub fn sort(&self, sources: &mut Vec<String>) {
+        let now = Instant::now();
+        let hosts = self.hosts.lock();
+        let mut keyed: Vec<(f64, String)> = sources
+            .drain(..)
+            .map(|source| {
+                let cost = host_of(&source)
+                    .and_then(|host| hosts.get(&host))
+                    .map_or(0.0, |stats| stats.cost(now));
+                (cost, source)
+            })
+            .collect();
+        keyed.sort_by(|a, b| a.0.total_cmp(&b.0));
+        sources.extend(keyed.into_iter().map(|(_, source)| source));
+    }
+
+    /// Stats of all known hosts, healthiest first.
+    pub fn snapshot(&self) -> Vec<HostHealth> {
+        let now = Instant::now();
+        let hosts = self.hosts.lock();
+        let mut list: Vec<(f64, HostHealth)> = hosts
+            .iter()
+            .map(|(host, stats)| {
+                let health = HostHealth {
+                    host: host.clone(),
+                    latency: stats.latency.map(Duration::from_secs_f64),
+                    throughput: stats.throughput.map(|t| t as u64),
+                    error_rate: stats.decayed_error_rate(now),
+                    requests: stats.requests,
+                    failures: stats.failures,
+                };
+                (stats.cost(now), health)
+            })
+            .collect();
+        list.sort_by(|a, b| a.0.total_cmp(&b.0));
+        list.into_iter().map(|(_, health)| health).collect()
+    }
+
+    /// Log stats of each host and drop idle hosts.
+    pub fn log_stats(&self) {
+        let now = Instant::now();
+        self.hosts
+            .lock()
+            .retain(|_, stats| now.duration_since(stats.last_update) < IDLE_TTL);
+        for host in self.snapshot() {
+            info!(
+                "Source health: host={}, latency={}, throughput={}, error_rate={:.2}, requests={}, failures={}",
+                host.host,
+                host.latency.map_or(String::from("-"), |l| format!("{}ms", l.as_millis())),
+                host.throughput.map_or(String::from("-"), |t| format!("{}KiB/s", t / 1024)),
+                host.error_rate,
+                host.requests,
+                host.failures
+            );
+        }
+    }
+}
+
+/// A request to an upstream host, dropped unfinished transfers count as failure unless cancelled.
+pub struct Transfer<'a> {
+    health: &'a SourceHealth,
+    host: Option<String>,
+    start: Instant,
+    /// Time when response headers received.
+    response: Option<Instant>,
+    bytes: u64,
+    /// Time spent by local throttling, not counted in throughput.
+    paused: Duration,
+    finished: bool,
+}
+
+impl Transfer<'_> {
+    /// Response headers received.
+    pub fn response(&mut self) {
+        self.response.get_or_insert_with(Instant::now);
+    }
+
+    /// Body data received.
+    pub fn received(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    /// Body reading was paused by local bandwidth limit.
+    pub fn paused(&mut self, time: Duration) {
+        self.paused += time;
+    }
+
+    /// Transfer stopped for a reason not caused by the host, like a local IO error, not counted.
+    pub fn cancel(mut self) {
+        self.finished = true;
+    }
+
+    /// Transfer completed successfully.
+    pub fn finish(mut self) {
+        self.finished = true;
+        let Some(host) = self.host.take() else {
+            return;
+        };
+        let now = Instant::now();
+        let response = self.response.unwrap_or(now);
+        let latency = response.duration_since(self.start).as_secs_f64();
+        let elapsed = now.duration_since(response).saturating_sub(self.paused).as_secs_f64();
+        let bytes = self.bytes;
+        self.health.update(host, true, |stats| {
+            stats.latency = Some(average(stats.latency, latency));
+            if bytes >= THROUGHPUT_MIN_BYTES && elapsed > 0.0 {
+                stats.throughput = Some(average(stats.throughput, bytes as f64 / elapsed));
+            }
+        });
+    }
+}
+
+impl Drop for Transfer<'_> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Some(host) = self.host.take() {
+            self.health.update(host, false, |_| {});
+        }
+    }
+}
@@ -3,8 +3,9 @@ use std::time::Duration;
 use const_format::concatcp;
 use futures::future::try_join_all;
 use openssl::sha::Sha1;
+use parking_lot::Mutex;
 use reqwest::Proxy;
-use tokio::fs::create_dir_all;
+use tokio::{fs::create_dir_all, time::Instant};
 
 use crate::CLIENT_VERSION;
 
@@ -38,3 +39,68 @@ pub fn create_http_client(timeout: Duration, proxy: Option<Proxy>) -> reqwest::C
 pub async fn create_dirs(dirs: Vec<&str>) -> Result<Vec<()>, std::io::Error> {
     try_join_all(dirs.iter().map(create_dir_all)).await
 }
+
+/// Parse size string like `500G`, `1.5TiB` or `1048576` to bytes.
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let unit = unit.trim().trim_end_matches(['B', 'b']).trim_end_matches('i');
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * multiplier as f64) as u64)
+}
+
+/// Size value parser for command line options.
+pub fn parse_size_arg(size: &str) -> Result<u64, String> {
+    parse_size(size).ok_or_else(|| format!("invalid size: {}", size))
+}
+
+/// Token bucket with 1 second burst, shared by all users of a bandwidth limit.
+pub struct TokenBucket {
+    state: Mutex<TokenBucketState>,
+}
+
+struct TokenBucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Default for TokenBucket {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(TokenBucketState {
+                tokens: f64::INFINITY, // Start full, capped to the burst size on first take
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl TokenBucket {
+    /// Take tokens for transferred data at `rate` bytes/sec, return how long to wait until the debt is paid.
+    pub fn take(&self, rate: u64, size: usize) -> Duration {
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let mut bucket = self.state.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.last_refill = now;
+        // Allow 1 second burst
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64) - size as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate as f64)
+        }
+    }
+}